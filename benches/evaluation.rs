@@ -0,0 +1,159 @@
+#[macro_use]
+extern crate criterion;
+extern crate dark;
+extern crate serde_json;
+
+use criterion::Criterion;
+
+use std::sync::Arc;
+
+use dark::{Client, ConfigBuilder, FeatureFlag, MemStore, Store, UserBuilder};
+
+const FALLTHROUGH: &'static str = r#"{
+    "key": "fallthrough",
+    "version": 1,
+    "on": true,
+    "prerequisites": [],
+    "salt": "salt",
+    "sel": "",
+    "targets": [],
+    "rules": [],
+    "fallthrough": {"variation": 1},
+    "offVariation": 0,
+    "variations": [false, true],
+    "deleted": false
+}"#;
+
+const TARGETED: &'static str = r#"{
+    "key": "targeted",
+    "version": 1,
+    "on": true,
+    "prerequisites": [],
+    "salt": "salt",
+    "sel": "",
+    "targets": [
+        {"values": ["a", "b", "c", "d", "e", "f", "g", "h", "user"], "variation": 1}
+    ],
+    "rules": [],
+    "fallthrough": {"variation": 0},
+    "offVariation": 0,
+    "variations": [false, true],
+    "deleted": false
+}"#;
+
+const RULE_MATCH: &'static str = r#"{
+    "key": "rule-match",
+    "version": 1,
+    "on": true,
+    "prerequisites": [{"key": "fallthrough", "variation": 1}],
+    "salt": "salt",
+    "sel": "",
+    "targets": [],
+    "rules": [
+        {
+            "id": "rule-0",
+            "variation": 1,
+            "clauses": [
+                {
+                    "attribute": "email",
+                    "op": "matches",
+                    "values": [".*@example\\.com$"],
+                    "negate": false
+                },
+                {
+                    "attribute": "signup",
+                    "op": "before",
+                    "values": ["2030-01-01T00:00:00Z"],
+                    "negate": false
+                }
+            ]
+        }
+    ],
+    "fallthrough": {"variation": 0},
+    "offVariation": 0,
+    "variations": ["off", "on"],
+    "deleted": false
+}"#;
+
+const ROLLOUT: &'static str = r#"{
+    "key": "rollout",
+    "version": 1,
+    "on": true,
+    "prerequisites": [],
+    "salt": "salt",
+    "sel": "",
+    "targets": [],
+    "rules": [],
+    "fallthrough": {
+        "rollout": {
+            "variations": [
+                {"variation": 0, "weight": 30000},
+                {"variation": 1, "weight": 30000},
+                {"variation": 2, "weight": 40000}
+            ],
+            "bucketBy": null
+        }
+    },
+    "offVariation": 0,
+    "variations": [0, 1, 2],
+    "deleted": false
+}"#;
+
+fn store() -> MemStore {
+    let store = MemStore::new();
+
+    for json in &[FALLTHROUGH, TARGETED, RULE_MATCH, ROLLOUT] {
+        let flag: FeatureFlag = serde_json::from_str(json).unwrap();
+        store.upsert(flag.key(), &flag).unwrap();
+    }
+
+    store
+}
+
+fn bench_flag_evaluate(c: &mut Criterion) {
+    let store = Arc::new(store());
+    let mut custom = ::std::collections::HashMap::new();
+    custom.insert("signup".to_string(), "2017-06-01T00:00:00Z".to_string());
+    let user = UserBuilder::new("user")
+        .email(Some("user@example.com".into()))
+        .custom(custom)
+        .build();
+
+    for key in &["fallthrough", "targeted", "rule-match", "rollout"] {
+        let flag = store.get(key).unwrap();
+        let s = store.clone();
+        let u = user.clone();
+
+        c.bench_function(&format!("flag_evaluate_{}", key), move |b| {
+            b.iter(|| flag.evaluate(&u, &s))
+        });
+    }
+}
+
+fn bench_client_evaluate(c: &mut Criterion) {
+    let config = ConfigBuilder::new().use_ldd(true).store(store()).build();
+    let client = Client::new("bench", config);
+    let user = UserBuilder::new("user").build();
+
+    c.bench_function("client_evaluate_rollout", move |b| {
+        b.iter(|| client.evaluate("rollout", &user, 0i64))
+    });
+}
+
+fn bench_user_bucket(c: &mut Criterion) {
+    let user = UserBuilder::new("userKeyA")
+        .secondary(Some("secondary".into()))
+        .build();
+
+    c.bench_function("user_bucket", move |b| {
+        b.iter(|| user.bucket("hashKey", "key", "saltyA", None))
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_flag_evaluate,
+    bench_client_evaluate,
+    bench_user_bucket
+);
+criterion_main!(benches);
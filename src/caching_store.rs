@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use feature_flag::FeatureFlag;
+use hash_cache::HashCache;
+use store::{Store, StoreResult, StoreStatus};
+
+const ALL_CACHE: &'static str = "$all_flags$";
+
+/// Caches reads from another store in memory, so that backends only have to
+/// persist flags. Writes go to the inner store and drop the entries they
+/// affect. Reads are not cached while the inner store is unavailable, nor when
+/// the cache was invalidated while they were in flight
+pub struct CachingStore<S: Store> {
+    store: S,
+    cache: HashCache<Option<Arc<FeatureFlag>>>,
+    all_cache: HashCache<HashMap<String, FeatureFlag>>,
+    generation: Arc<Mutex<usize>>,
+    initialized: AtomicBool,
+}
+
+/// Drops entries from the cache of a `CachingStore`, for backends that are
+/// notified of changes written by other processes
+#[derive(Clone)]
+pub struct CacheInvalidator {
+    cache: HashCache<Option<Arc<FeatureFlag>>>,
+    all_cache: HashCache<HashMap<String, FeatureFlag>>,
+    // Bumped on every invalidation, under its lock, so that reads started
+    // before it do not fill the cache with what they read
+    generation: Arc<Mutex<usize>>,
+}
+
+impl CacheInvalidator {
+    pub fn invalidate(&self, key: &str) {
+        let mut generation = self.generation.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+
+        self.cache.remove(key);
+        self.all_cache.remove(ALL_CACHE);
+    }
+
+    pub fn invalidate_all(&self) {
+        let mut generation = self.generation.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+
+        self.cache.clear();
+        self.all_cache.clear();
+    }
+}
+
+impl<S: Store> CachingStore<S> {
+    /// Keeps flags for `ttl` before reading them again. A zero `ttl` caches
+    /// them forever
+    pub fn new(store: S, ttl: Duration) -> CachingStore<S> {
+        CachingStore {
+            store: store,
+            cache: HashCache::new(ttl),
+            all_cache: HashCache::new(ttl),
+            generation: Arc::new(Mutex::new(0)),
+            initialized: AtomicBool::new(false),
+        }
+    }
+
+    /// Reads every flag from the inner store at most once, for stores that
+    /// are only written through this one
+    pub fn forever(store: S) -> CachingStore<S> {
+        CachingStore::new(store, Duration::new(0, 0))
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    pub fn invalidator(&self) -> CacheInvalidator {
+        CacheInvalidator {
+            cache: self.cache.clone(),
+            all_cache: self.all_cache.clone(),
+            generation: self.generation.clone(),
+        }
+    }
+
+    fn invalidate(&self, key: &str) {
+        self.invalidator().invalidate(key);
+    }
+
+    fn generation(&self) -> usize {
+        *self.generation.lock().unwrap()
+    }
+
+    // Caches a result read from the inner store unless the cache was
+    // invalidated since `generation`, as the result may predate the change.
+    // Results read while the inner store is unavailable may be stale or
+    // missing, and must not outlive the outage
+    fn fill<F: FnOnce()>(&self, generation: usize, fill: F) {
+        if self.store.status() != StoreStatus::Available {
+            return;
+        }
+
+        let current = self.generation.lock().unwrap();
+        if *current == generation {
+            fill();
+        }
+    }
+}
+
+impl<S: Store> Store for CachingStore<S> {
+    fn get(&self, key: &str) -> Option<Arc<FeatureFlag>> {
+        if let Some(cached) = self.cache.get(key) {
+            return cached;
+        }
+
+        // Missing flags are cached too, so that unknown keys do not reach the
+        // inner store on every evaluation
+        let generation = self.generation();
+        let flag = self.store.get(key);
+        self.fill(generation, || {
+            self.cache.insert(key, flag.clone());
+        });
+        flag
+    }
+
+    fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
+        if let Some(map) = self.all_cache.get(ALL_CACHE) {
+            return Ok(map);
+        }
+
+        let generation = self.generation();
+        let map = self.store.get_all()?;
+        self.fill(generation, || {
+            self.all_cache.insert(ALL_CACHE, map.clone());
+        });
+        Ok(map)
+    }
+
+    fn delete(&self, key: &str, version: usize) -> StoreResult<()> {
+        let res = self.store.delete(key, version);
+        self.invalidate(key);
+        res
+    }
+
+    fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
+        let res = self.store.upsert(key, flag);
+        self.invalidate(key);
+        res
+    }
+
+    fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
+        self.store.init(flags.clone())?;
+
+        let mut all = flags;
+        all.retain(|_, flag| !flag.deleted());
+
+        // Reads in flight return flags from before the new data set
+        let mut generation = self.generation.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+
+        self.cache.replace(
+            all.iter()
+                .map(|(key, flag)| (key.clone(), Some(Arc::new(flag.clone()))))
+                .collect(),
+        );
+        self.all_cache.insert(ALL_CACHE, all);
+        drop(generation);
+        self.initialized.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn initialized(&self) -> bool {
+        if self.initialized.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let initialized = self.store.initialized();
+        if initialized {
+            self.initialized.store(true, Ordering::SeqCst);
+        }
+
+        initialized
+    }
+
+    fn status(&self) -> StoreStatus {
+        self.store.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+
+    use caching_store::*;
+    use conformance::flag;
+    use mem_store::MemStore;
+
+    struct CountingStore {
+        store: MemStore,
+        reads: AtomicUsize,
+        available: AtomicBool,
+        // Holds reads after the inner store answered them until resumed
+        paused: Mutex<Option<(Sender<()>, Receiver<()>)>>,
+    }
+
+    impl CountingStore {
+        fn new() -> CountingStore {
+            CountingStore {
+                store: MemStore::new(),
+                reads: AtomicUsize::new(0),
+                available: AtomicBool::new(true),
+                paused: Mutex::new(None),
+            }
+        }
+
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+
+        fn read<T>(&self, value: T) -> T {
+            if let Some((ref read, ref resume)) = *self.paused.lock().unwrap() {
+                read.send(()).unwrap();
+                resume.recv().unwrap();
+            }
+
+            value
+        }
+    }
+
+    impl Store for CountingStore {
+        fn get(&self, key: &str) -> Option<Arc<FeatureFlag>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.read(self.store.get(key))
+        }
+
+        fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.read(self.store.get_all())
+        }
+
+        fn delete(&self, key: &str, version: usize) -> StoreResult<()> {
+            self.store.delete(key, version)
+        }
+
+        fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
+            self.store.upsert(key, flag)
+        }
+
+        fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
+            self.store.init(flags)
+        }
+
+        fn initialized(&self) -> bool {
+            self.store.initialized()
+        }
+
+        fn status(&self) -> StoreStatus {
+            if self.available.load(Ordering::SeqCst) {
+                StoreStatus::Available
+            } else {
+                StoreStatus::Unavailable {
+                    last_error: "down".into(),
+                    since: ::std::time::SystemTime::now(),
+                }
+            }
+        }
+    }
+
+    store_conformance_tests!(|| CachingStore::forever(MemStore::new()));
+
+    #[test]
+    fn test_caches_reads() {
+        let store = CachingStore::forever(CountingStore::new());
+        store.inner().upsert("f1", &flag("f1", 1, false)).unwrap();
+
+        assert!(store.get("f1").is_some());
+        assert!(store.get("f1").is_some());
+        assert!(store.get("f2").is_none());
+        assert!(store.get("f2").is_none());
+        assert_eq!(store.get_all().unwrap().len(), 1);
+        assert_eq!(store.get_all().unwrap().len(), 1);
+
+        assert_eq!(store.inner().reads(), 3);
+    }
+
+    #[test]
+    fn test_writes_invalidate() {
+        let store = CachingStore::forever(CountingStore::new());
+        store.upsert("f1", &flag("f1", 1, false)).unwrap();
+        assert_eq!(store.get("f1").unwrap().version(), 1);
+        assert_eq!(store.get_all().unwrap().len(), 1);
+
+        store.upsert("f1", &flag("f1", 2, false)).unwrap();
+        assert_eq!(store.get("f1").unwrap().version(), 2);
+
+        store.delete("f1", 3).unwrap();
+        assert!(store.get("f1").is_none());
+        assert!(store.get_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expires_after_ttl() {
+        let store = CachingStore::new(CountingStore::new(), Duration::from_millis(20));
+        store.inner().upsert("f1", &flag("f1", 1, false)).unwrap();
+        store.get("f1");
+
+        store.inner().upsert("f1", &flag("f1", 2, false)).unwrap();
+        assert_eq!(store.get("f1").unwrap().version(), 1);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("f1").unwrap().version(), 2);
+    }
+
+    #[test]
+    fn test_init_fills_cache() {
+        let store = CachingStore::forever(CountingStore::new());
+        let mut flags = HashMap::new();
+        flags.insert("f1".to_string(), flag("f1", 1, false));
+        store.init(flags).unwrap();
+
+        assert!(store.initialized());
+        assert!(store.get("f1").is_some());
+        assert_eq!(store.get_all().unwrap().len(), 1);
+        assert_eq!(store.inner().reads(), 0);
+    }
+
+    #[test]
+    fn test_invalidator_drops_entries() {
+        let store = CachingStore::forever(CountingStore::new());
+        let invalidator = store.invalidator();
+        store.inner().upsert("f1", &flag("f1", 1, false)).unwrap();
+        store.inner().upsert("f2", &flag("f2", 1, false)).unwrap();
+        store.get("f1");
+        store.get("f2");
+
+        store.inner().upsert("f1", &flag("f1", 2, false)).unwrap();
+        store.inner().upsert("f2", &flag("f2", 2, false)).unwrap();
+        invalidator.invalidate("f1");
+        assert_eq!(store.get("f1").unwrap().version(), 2);
+        assert_eq!(store.get("f2").unwrap().version(), 1);
+
+        invalidator.invalidate_all();
+        assert_eq!(store.get("f2").unwrap().version(), 2);
+    }
+
+    #[test]
+    fn test_reads_racing_changes_are_not_cached() {
+        let store = Arc::new(CachingStore::forever(CountingStore::new()));
+        store.inner().upsert("f1", &flag("f1", 1, false)).unwrap();
+
+        let (read_tx, read_rx) = channel();
+        let (resume_tx, resume_rx) = channel();
+        *store.inner().paused.lock().unwrap() = Some((read_tx, resume_rx));
+
+        // Another process changes the flag while the read is in flight
+        let reader = {
+            let store = store.clone();
+            thread::spawn(move || store.get("f1").unwrap().version())
+        };
+        read_rx.recv().unwrap();
+        store.inner().upsert("f1", &flag("f1", 2, false)).unwrap();
+        store.invalidator().invalidate("f1");
+        resume_tx.send(()).unwrap();
+        assert_eq!(reader.join().unwrap(), 1);
+
+        // A write through the cache races with a read of every flag
+        let reader = {
+            let store = store.clone();
+            thread::spawn(move || store.get_all().unwrap()["f1"].version())
+        };
+        read_rx.recv().unwrap();
+        store.upsert("f1", &flag("f1", 3, false)).unwrap();
+        resume_tx.send(()).unwrap();
+        assert_eq!(reader.join().unwrap(), 2);
+
+        *store.inner().paused.lock().unwrap() = None;
+        assert_eq!(store.get("f1").unwrap().version(), 3);
+        assert_eq!(store.get_all().unwrap()["f1"].version(), 3);
+    }
+
+    #[test]
+    fn test_does_not_cache_while_unavailable() {
+        let store = CachingStore::forever(CountingStore::new());
+        store.inner().available.store(false, Ordering::SeqCst);
+        assert!(store.get("f1").is_none());
+        assert!(store.get_all().unwrap().is_empty());
+
+        store.inner().upsert("f1", &flag("f1", 1, false)).unwrap();
+        store.inner().available.store(true, Ordering::SeqCst);
+        assert!(store.get("f1").is_some());
+        assert_eq!(store.get_all().unwrap().len(), 1);
+    }
+}
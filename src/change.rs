@@ -0,0 +1,335 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use feature_flag::FeatureFlag;
+use store::{Store, StoreResult};
+
+type Listener = Arc<Fn(&str) + Send + Sync>;
+
+/// Listeners notified with the key of every flag changed by a data source,
+/// including flags whose prerequisites changed. Clones share the same
+/// listeners
+#[derive(Clone, Default)]
+pub struct FlagChanges {
+    listeners: Arc<RwLock<Vec<Listener>>>,
+    // Held while writing to the store, so that the graph follows the order
+    // of the writes
+    graph: Arc<Mutex<Graph>>,
+}
+
+impl FlagChanges {
+    pub fn new() -> FlagChanges {
+        FlagChanges::default()
+    }
+
+    /// Listeners are called on the data source thread once the store has been
+    /// written, and may register further listeners
+    pub fn listen<F>(&self, listener: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.listeners.write().unwrap().push(Arc::new(listener));
+    }
+
+    fn has_listeners(&self) -> bool {
+        !self.listeners.read().unwrap().is_empty()
+    }
+
+    pub fn upsert<S: Store>(&self, store: &S, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
+        let changed = {
+            let mut graph = self.graph.lock().unwrap();
+            store.upsert(key, flag)?;
+            graph.set(key, Some(flag));
+            graph.dependents(vec![key.to_string()])
+        };
+
+        self.notify(changed);
+        Ok(())
+    }
+
+    pub fn delete<S: Store>(&self, store: &S, key: &str, version: usize) -> StoreResult<()> {
+        let changed = {
+            let mut graph = self.graph.lock().unwrap();
+            store.delete(key, version)?;
+            graph.set(key, None);
+            graph.dependents(vec![key.to_string()])
+        };
+
+        self.notify(changed);
+        Ok(())
+    }
+
+    // A full payload only changes the flags whose versions differ from those
+    // already stored, or that were added or removed
+    pub fn init<S: Store>(
+        &self,
+        store: &S,
+        flags: HashMap<String, FeatureFlag>,
+    ) -> StoreResult<()> {
+        let versions = |flags: &HashMap<String, FeatureFlag>| {
+            flags
+                .iter()
+                .map(|(key, flag)| (key.clone(), flag.version()))
+                .collect::<HashMap<String, usize>>()
+        };
+
+        let changed = {
+            let mut graph = self.graph.lock().unwrap();
+
+            let old = if self.has_listeners() {
+                Some(versions(&store.get_all()?))
+            } else {
+                None
+            };
+            let new = versions(&flags);
+            let replacement = Graph::new(&flags);
+
+            store.init(flags)?;
+            *graph = replacement;
+
+            match old {
+                Some(old) => graph.dependents(
+                    old.keys()
+                        .chain(new.keys())
+                        .filter(|key| old.get(*key) != new.get(*key))
+                        .cloned()
+                        .collect::<HashSet<String>>(),
+                ),
+                None => vec![],
+            }
+        };
+
+        self.notify(changed);
+        Ok(())
+    }
+
+    // Listeners are called without holding any lock, so that they may read
+    // the store or register listeners
+    fn notify(&self, changed: Vec<String>) {
+        let listeners = self.listeners.read().unwrap().clone();
+
+        for key in changed {
+            for listener in listeners.iter() {
+                listener(key.as_str());
+            }
+        }
+    }
+}
+
+// Prerequisites of the flags written through `FlagChanges`, so that the flags
+// depending on a changed one are found without reading every flag
+#[derive(Default)]
+struct Graph {
+    prerequisites: HashMap<String, Vec<String>>,
+    // The reverse of `prerequisites`
+    dependents: HashMap<String, HashSet<String>>,
+}
+
+impl Graph {
+    fn new(flags: &HashMap<String, FeatureFlag>) -> Graph {
+        let mut graph = Graph::default();
+        for (key, flag) in flags {
+            graph.set(key.as_str(), Some(flag));
+        }
+        graph
+    }
+
+    // Deleted flags have no prerequisites, but flags depending on them keep
+    // their edges until they change themselves
+    fn set(&mut self, key: &str, flag: Option<&FeatureFlag>) {
+        for prereq in self.prerequisites.remove(key).unwrap_or_default() {
+            let unused = match self.dependents.get_mut(prereq.as_str()) {
+                Some(dependents) => {
+                    dependents.remove(key);
+                    dependents.is_empty()
+                }
+                None => false,
+            };
+
+            if unused {
+                self.dependents.remove(prereq.as_str());
+            }
+        }
+
+        let prereqs = match flag {
+            Some(flag) if !flag.deleted() => flag
+                .prerequisites()
+                .iter()
+                .map(|prereq| prereq.key.clone())
+                .collect::<Vec<String>>(),
+            _ => return,
+        };
+
+        for prereq in &prereqs {
+            self.dependents
+                .entry(prereq.clone())
+                .or_insert_with(HashSet::new)
+                .insert(key.to_string());
+        }
+
+        if !prereqs.is_empty() {
+            self.prerequisites.insert(key.to_string(), prereqs);
+        }
+    }
+
+    // Expands the changed keys with every flag that depends on them, directly
+    // or transitively, through its prerequisites
+    fn dependents<I>(&self, changed: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut seen = HashSet::new();
+        let mut result = vec![];
+        let mut queue = changed.into_iter().collect::<Vec<String>>();
+
+        while let Some(key) = queue.pop() {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+
+            if let Some(keys) = self.dependents.get(key.as_str()) {
+                queue.extend(keys.iter().cloned());
+            }
+
+            result.push(key);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use change::*;
+    use feature_flag::{Prerequisite, VariationOrRollOut};
+    use mem_store::MemStore;
+
+    fn flag(key: &str, version: usize, prereqs: Vec<&str>) -> FeatureFlag {
+        FeatureFlag::new(
+            key.into(),
+            version,
+            true,
+            prereqs
+                .into_iter()
+                .map(|p| Prerequisite {
+                    key: p.into(),
+                    variation: 0,
+                })
+                .collect(),
+            "salt".into(),
+            "sel".into(),
+            vec![],
+            vec![],
+            VariationOrRollOut::Variation(0),
+            Some(0),
+            vec![],
+            false,
+        )
+    }
+
+    fn recorder(changes: &FlagChanges) -> Arc<Mutex<Vec<String>>> {
+        let keys = Arc::new(Mutex::new(vec![]));
+        let k = keys.clone();
+        changes.listen(move |key| k.lock().unwrap().push(key.to_string()));
+        keys
+    }
+
+    fn sorted(keys: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+        let mut keys = keys.lock().unwrap().drain(..).collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_init_reports_changed_flags() {
+        let store = MemStore::new();
+        let changes = FlagChanges::new();
+        let keys = recorder(&changes);
+
+        let mut flags = HashMap::new();
+        flags.insert("a".to_string(), flag("a", 1, vec![]));
+        flags.insert("b".to_string(), flag("b", 1, vec![]));
+        changes.init(&store, flags).unwrap();
+        assert_eq!(sorted(&keys), vec!["a", "b"]);
+
+        let mut flags = HashMap::new();
+        flags.insert("a".to_string(), flag("a", 1, vec![]));
+        flags.insert("c".to_string(), flag("c", 1, vec![]));
+        changes.init(&store, flags).unwrap();
+        assert_eq!(sorted(&keys), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_reports_prerequisite_dependents() {
+        let store = MemStore::new();
+        let changes = FlagChanges::new();
+
+        changes.upsert(&store, "a", &flag("a", 1, vec![])).unwrap();
+        changes
+            .upsert(&store, "b", &flag("b", 1, vec!["a"]))
+            .unwrap();
+        changes
+            .upsert(&store, "c", &flag("c", 1, vec!["b"]))
+            .unwrap();
+        changes.upsert(&store, "d", &flag("d", 1, vec![])).unwrap();
+
+        let keys = recorder(&changes);
+
+        changes.upsert(&store, "a", &flag("a", 2, vec![])).unwrap();
+        assert_eq!(sorted(&keys), vec!["a", "b", "c"]);
+
+        changes.delete(&store, "b", 2).unwrap();
+        assert_eq!(sorted(&keys), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_dependents_follow_changed_prerequisites() {
+        let store = MemStore::new();
+        let changes = FlagChanges::new();
+
+        let mut flags = HashMap::new();
+        flags.insert("a".to_string(), flag("a", 1, vec![]));
+        flags.insert("b".to_string(), flag("b", 1, vec!["a"]));
+        changes.init(&store, flags).unwrap();
+
+        let keys = recorder(&changes);
+
+        changes.upsert(&store, "b", &flag("b", 2, vec![])).unwrap();
+        assert_eq!(sorted(&keys), vec!["b"]);
+
+        changes.upsert(&store, "a", &flag("a", 2, vec![])).unwrap();
+        assert_eq!(sorted(&keys), vec!["a"]);
+    }
+
+    #[test]
+    fn test_listeners_may_register_listeners() {
+        let store = MemStore::new();
+        let changes = FlagChanges::new();
+        let keys = Arc::new(Mutex::new(vec![]));
+
+        let c = changes.clone();
+        let k = keys.clone();
+        changes.listen(move |_| {
+            let k = k.clone();
+            c.listen(move |key| k.lock().unwrap().push(key.to_string()));
+        });
+
+        changes.upsert(&store, "a", &flag("a", 1, vec![])).unwrap();
+        changes.upsert(&store, "a", &flag("a", 2, vec![])).unwrap();
+        assert_eq!(sorted(&keys), vec!["a"]);
+    }
+
+    #[test]
+    fn test_ignores_rejected_updates() {
+        let store = MemStore::new();
+        let changes = FlagChanges::new();
+        changes.upsert(&store, "a", &flag("a", 2, vec![])).unwrap();
+
+        let keys = recorder(&changes);
+
+        assert!(changes.upsert(&store, "a", &flag("a", 1, vec![])).is_err());
+        assert!(sorted(&keys).is_empty());
+    }
+}
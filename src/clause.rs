@@ -1,18 +1,73 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
 use regex::Regex;
+use semver::Version;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use feature_flag::VariationValue;
 use user::User;
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Clause {
     attribute: String,
     op: Operator,
     values: Vec<VariationValue>,
     negate: bool,
+    #[serde(skip_serializing)]
+    preprocessed: Vec<Preprocessed>,
+}
+
+#[derive(Deserialize)]
+struct RawClause {
+    attribute: String,
+    op: Operator,
+    #[serde(default)]
+    values: Vec<VariationValue>,
+    #[serde(default)]
+    negate: bool,
+}
+
+impl<'de> Deserialize<'de> for Clause {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawClause::deserialize(deserializer)?;
+        Ok(Clause::new(raw.attribute, raw.op, raw.values, raw.negate))
+    }
+}
+
+// Preprocessed values are derived entirely from the op and values, so they
+// are excluded from comparisons
+impl PartialEq for Clause {
+    fn eq(&self, other: &Clause) -> bool {
+        self.attribute == other.attribute
+            && self.op == other.op
+            && self.values == other.values
+            && self.negate == other.negate
+    }
 }
 
 impl Clause {
+    pub fn new(
+        attribute: String,
+        op: Operator,
+        values: Vec<VariationValue>,
+        negate: bool,
+    ) -> Clause {
+        let preprocessed = values
+            .iter()
+            .map(|value| Preprocessed::new(&op, value))
+            .collect();
+
+        Clause {
+            attribute: attribute,
+            op: op,
+            values: values,
+            negate: negate,
+            preprocessed: preprocessed,
+        }
+    }
+
     pub fn matches_user(&self, user: &User) -> bool {
         if let Some(val) = user.get_for_eval(self.attribute.as_str()) {
             // TODO: Add handling for non-string values coming from user data
@@ -25,7 +80,8 @@ impl Clause {
     pub fn match_any(&self, val: VariationValue) -> bool {
         self.values
             .iter()
-            .fold(false, |pass, v| pass || self.op.apply(v, &val))
+            .zip(self.preprocessed.iter())
+            .any(|(v, pre)| self.op.apply_preprocessed(&val, v, pre))
     }
 
     fn handle_negate(&self, status: bool) -> bool {
@@ -37,7 +93,44 @@ impl Clause {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// Clause values in the form needed by their operator, computed once when the
+/// clause is built rather than on every evaluation
+#[derive(Clone, Debug)]
+pub enum Preprocessed {
+    Regex(Regex),
+    Time(DateTime<Utc>),
+    SemVer(Version),
+    Invalid,
+    Raw,
+}
+
+impl Preprocessed {
+    pub fn new(op: &Operator, value: &VariationValue) -> Preprocessed {
+        match *op {
+            Operator::Matches => match *value {
+                VariationValue::String(ref pattern) => match Regex::new(pattern) {
+                    Ok(re) => Preprocessed::Regex(re),
+                    Err(err) => {
+                        warn!("Invalid regex {:?} in clause: {}", pattern, err);
+                        Preprocessed::Invalid
+                    }
+                },
+                _ => Preprocessed::Invalid,
+            },
+            Operator::Before | Operator::After => value_to_time(value)
+                .map(Preprocessed::Time)
+                .unwrap_or(Preprocessed::Invalid),
+            Operator::SemVerEqual | Operator::SemVerLessThan | Operator::SemVerGreaterThan => {
+                value_to_semver(value)
+                    .map(Preprocessed::SemVer)
+                    .unwrap_or(Preprocessed::Invalid)
+            }
+            _ => Preprocessed::Raw,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Operator {
     In,
     EndsWith,
@@ -50,10 +143,79 @@ pub enum Operator {
     GreaterThanOrEqual,
     Before,
     After,
+    SemVerEqual,
+    SemVerLessThan,
+    SemVerGreaterThan,
+    Unknown(String),
+}
+
+impl Serialize for Operator {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(match *self {
+            Operator::In => "in",
+            Operator::EndsWith => "endsWith",
+            Operator::StartsWith => "startsWith",
+            Operator::Matches => "matches",
+            Operator::Contains => "contains",
+            Operator::LessThan => "lessThan",
+            Operator::LessThanOrEqual => "lessThanOrEqual",
+            Operator::GreaterThan => "greaterThan",
+            Operator::GreaterThanOrEqual => "greaterThanOrEqual",
+            Operator::Before => "before",
+            Operator::After => "after",
+            Operator::SemVerEqual => "semVerEqual",
+            Operator::SemVerLessThan => "semVerLessThan",
+            Operator::SemVerGreaterThan => "semVerGreaterThan",
+            Operator::Unknown(ref op) => op.as_str(),
+        })
+    }
+}
+
+// Operators this client does not know about (e.g. segmentMatch) are kept so
+// that the rest of the flag can still be used, but never match
+impl<'de> Deserialize<'de> for Operator {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(match s.as_str() {
+            "in" => Operator::In,
+            "endsWith" => Operator::EndsWith,
+            "startsWith" => Operator::StartsWith,
+            "matches" => Operator::Matches,
+            "contains" => Operator::Contains,
+            "lessThan" => Operator::LessThan,
+            "lessThanOrEqual" => Operator::LessThanOrEqual,
+            "greaterThan" => Operator::GreaterThan,
+            "greaterThanOrEqual" => Operator::GreaterThanOrEqual,
+            "before" => Operator::Before,
+            "after" => Operator::After,
+            "semVerEqual" => Operator::SemVerEqual,
+            "semVerLessThan" => Operator::SemVerLessThan,
+            "semVerGreaterThan" => Operator::SemVerGreaterThan,
+            other => {
+                warn!("Unsupported clause operator {:?}", other);
+                Operator::Unknown(other.into())
+            }
+        })
+    }
 }
 
 impl Operator {
     pub fn apply(&self, a: &VariationValue, b: &VariationValue) -> bool {
+        self.apply_preprocessed(a, b, &Preprocessed::new(self, b))
+    }
+
+    pub fn apply_preprocessed(
+        &self,
+        a: &VariationValue,
+        b: &VariationValue,
+        pre: &Preprocessed,
+    ) -> bool {
         match *self {
             Operator::In => match (a, b) {
                 (&VariationValue::Integer(ref a_v), &VariationValue::Float(ref b_v)) => {
@@ -76,13 +238,9 @@ impl Operator {
                 }
                 _ => false,
             },
-            Operator::Matches => match (a, b) {
-                (&VariationValue::String(ref a_v), &VariationValue::String(ref b_v)) => {
-                    if let Ok(re) = Regex::new(b_v) {
-                        re.is_match(a_v)
-                    } else {
-                        false
-                    }
+            Operator::Matches => match (a, pre) {
+                (&VariationValue::String(ref a_v), &Preprocessed::Regex(ref re)) => {
+                    re.is_match(a_v)
                 }
                 _ => false,
             },
@@ -140,14 +298,27 @@ impl Operator {
                 (&VariationValue::Float(ref a_v), &VariationValue::Float(ref b_v)) => a_v >= b_v,
                 _ => false,
             },
-            Operator::Before => match (value_to_time(a), value_to_time(b)) {
-                (Some(date_a), Some(date_b)) => date_a < date_b,
+            Operator::Before => match (value_to_time(a), pre) {
+                (Some(date_a), &Preprocessed::Time(ref date_b)) => date_a < *date_b,
                 _ => false,
             },
-            Operator::After => match (value_to_time(a), value_to_time(b)) {
-                (Some(date_a), Some(date_b)) => date_a > date_b,
+            Operator::After => match (value_to_time(a), pre) {
+                (Some(date_a), &Preprocessed::Time(ref date_b)) => date_a > *date_b,
                 _ => false,
             },
+            Operator::SemVerEqual => match (value_to_semver(a), pre) {
+                (Some(ver_a), &Preprocessed::SemVer(ref ver_b)) => ver_a == *ver_b,
+                _ => false,
+            },
+            Operator::SemVerLessThan => match (value_to_semver(a), pre) {
+                (Some(ver_a), &Preprocessed::SemVer(ref ver_b)) => ver_a < *ver_b,
+                _ => false,
+            },
+            Operator::SemVerGreaterThan => match (value_to_semver(a), pre) {
+                (Some(ver_a), &Preprocessed::SemVer(ref ver_b)) => ver_a > *ver_b,
+                _ => false,
+            },
+            Operator::Unknown(_) => false,
         }
     }
 }
@@ -196,9 +367,36 @@ fn value_to_time(v: &VariationValue) -> Option<DateTime<Utc>> {
     }
 }
 
+// Versions missing a minor or patch component ("2", "2.1") are padded with
+// zeros before parsing
+fn value_to_semver(v: &VariationValue) -> Option<Version> {
+    match *v {
+        VariationValue::String(ref v_v) => {
+            let mut candidate = v_v.clone();
+
+            for _ in 0..3 {
+                if let Ok(version) = Version::parse(candidate.as_str()) {
+                    return Some(version);
+                }
+
+                let split = candidate
+                    .find(|c: char| c == '-' || c == '+')
+                    .unwrap_or(candidate.len());
+                candidate.insert_str(split, ".0");
+            }
+
+            None
+        }
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use clause::{Operator, VariationValue};
+    use std::collections::HashMap;
+
+    use clause::{Clause, Operator, Preprocessed, VariationValue};
+    use user::UserBuilder;
 
     #[test]
     fn test_op_in() {
@@ -816,4 +1014,202 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_op_semver() {
+        let tests = vec![
+            (Operator::SemVerEqual, "2.0.0", "2.0.0", true),
+            (Operator::SemVerEqual, "2.0", "2.0.0", true),
+            (Operator::SemVerEqual, "2", "2.0.0", true),
+            (Operator::SemVerEqual, "2.0.1", "2.0.0", false),
+            (Operator::SemVerLessThan, "2.0.0", "2.0.1", true),
+            (Operator::SemVerLessThan, "2.0.0-rc.1", "2.0.0", true),
+            (Operator::SemVerLessThan, "2.0.1", "2.0.0", false),
+            (Operator::SemVerGreaterThan, "2.0.1", "2.0.0", true),
+            (Operator::SemVerGreaterThan, "2.0.0", "2.0.1", false),
+            (Operator::SemVerGreaterThan, "not.a.version", "2.0.0", false),
+        ];
+
+        for (op, a, b, res) in tests {
+            assert_eq!(
+                op.apply(
+                    &VariationValue::String(a.into()),
+                    &VariationValue::String(b.into())
+                ),
+                res,
+                "{:?} {:?} {:?}",
+                a,
+                op,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn test_clause_preprocesses_values() {
+        let clause = Clause::new(
+            "email".into(),
+            Operator::Matches,
+            vec![
+                VariationValue::String(".*@example\\.com$".into()),
+                VariationValue::String("(unclosed".into()),
+            ],
+            false,
+        );
+
+        match clause.preprocessed[0] {
+            Preprocessed::Regex(_) => (),
+            ref other => panic!("Expected compiled regex, found {:?}", other),
+        }
+
+        match clause.preprocessed[1] {
+            Preprocessed::Invalid => (),
+            ref other => panic!("Expected invalid regex, found {:?}", other),
+        }
+
+        let matching = UserBuilder::new("a")
+            .email(Some("user@example.com".into()))
+            .build();
+        let other = UserBuilder::new("b")
+            .email(Some("user@example.org".into()))
+            .build();
+
+        assert!(clause.matches_user(&matching));
+        assert!(!clause.matches_user(&other));
+    }
+
+    #[test]
+    fn test_clause_compares_user_value_against_clause_value() {
+        let mut custom = HashMap::new();
+        custom.insert("signup".to_string(), "1970-01-01T00:00:01Z".to_string());
+        let user = UserBuilder::new("a").custom(custom).build();
+
+        let before = Clause::new(
+            "signup".into(),
+            Operator::Before,
+            vec![VariationValue::String("1970-01-01T00:00:02Z".into())],
+            false,
+        );
+        let after = Clause::new(
+            "signup".into(),
+            Operator::After,
+            vec![VariationValue::String("1970-01-01T00:00:02Z".into())],
+            false,
+        );
+
+        assert!(before.matches_user(&user));
+        assert!(!after.matches_user(&user));
+    }
+
+    fn clause(op: Operator, value: VariationValue) -> Clause {
+        Clause::new("attr".into(), op, vec![value], false)
+    }
+
+    #[test]
+    fn test_clause_applies_operator_to_user_value() {
+        let string = |s: &str| VariationValue::String(s.into());
+        let tests = vec![
+            (Operator::StartsWith, string("prefix and more"), string("prefix"), true),
+            (Operator::StartsWith, string("prefix"), string("prefix and more"), false),
+            (Operator::EndsWith, string("more and suffix"), string("suffix"), true),
+            (Operator::EndsWith, string("suffix"), string("more and suffix"), false),
+            (Operator::Contains, string("a needle here"), string("needle"), true),
+            (Operator::Contains, string("needle"), string("a needle here"), false),
+            (
+                Operator::Matches,
+                string("user@example.com"),
+                string(".*@example\\.com$"),
+                true,
+            ),
+            (
+                Operator::Matches,
+                string(".*@example\\.com$"),
+                string("user@example.com"),
+                false,
+            ),
+            (
+                Operator::LessThan,
+                VariationValue::Integer(1),
+                VariationValue::Integer(2),
+                true,
+            ),
+            (
+                Operator::LessThan,
+                VariationValue::Integer(2),
+                VariationValue::Integer(1),
+                false,
+            ),
+            (
+                Operator::LessThanOrEqual,
+                VariationValue::Float(1.5),
+                VariationValue::Integer(2),
+                true,
+            ),
+            (
+                Operator::LessThanOrEqual,
+                VariationValue::Integer(3),
+                VariationValue::Float(2.5),
+                false,
+            ),
+            (
+                Operator::GreaterThan,
+                VariationValue::Integer(2),
+                VariationValue::Integer(1),
+                true,
+            ),
+            (
+                Operator::GreaterThan,
+                VariationValue::Integer(1),
+                VariationValue::Integer(2),
+                false,
+            ),
+            (
+                Operator::GreaterThanOrEqual,
+                VariationValue::Float(2.5),
+                VariationValue::Integer(2),
+                true,
+            ),
+            (
+                Operator::GreaterThanOrEqual,
+                VariationValue::Integer(1),
+                VariationValue::Float(1.5),
+                false,
+            ),
+            (
+                Operator::Before,
+                string("1970-01-01T00:00:01Z"),
+                string("1970-01-01T00:00:02Z"),
+                true,
+            ),
+            (
+                Operator::Before,
+                string("1970-01-01T00:00:02Z"),
+                string("1970-01-01T00:00:01Z"),
+                false,
+            ),
+            (
+                Operator::After,
+                string("1970-01-01T00:00:02Z"),
+                string("1970-01-01T00:00:01Z"),
+                true,
+            ),
+            (
+                Operator::After,
+                string("1970-01-01T00:00:01Z"),
+                string("1970-01-01T00:00:02Z"),
+                false,
+            ),
+        ];
+
+        for (op, user_value, clause_value, res) in tests {
+            assert_eq!(
+                clause(op.clone(), clause_value.clone()).match_any(user_value.clone()),
+                res,
+                "{:?} {:?} {:?}",
+                user_value,
+                op,
+                clause_value
+            );
+        }
+    }
 }
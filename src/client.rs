@@ -1,14 +1,18 @@
-use std::sync::Arc;
-use std::sync::mpsc::channel;
-use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use change::FlagChanges;
 use config::Config;
-use events::{EventProcessor, EventSender};
-use feature_flag::{Eval, FeatureFlag, VariationValue};
+use events::{Event, EventProcessor, EventSender, FeatureRequestEvent, ServerTime};
+use feature_flag::{ErrorKind, Eval, Explanation, FeatureFlag, VariationValue};
+use file_source::FileDataSource;
 use mem_store::MemStore;
 use poll::Polling;
 use request::Requestor;
-use store::Store;
+use status::{DataSourceStatus, StatusTracker};
+use store::{Store, StoreStatus};
 use stream::Streaming;
 use user::User;
 
@@ -19,75 +23,250 @@ use user::User;
 // pub flush_interval: i64, /
 // pub sampling_interval: i64, /
 // pub poll_interval: i64, /
-// pub timeout: i64, - Not implemented
+// pub timeout: Duration, /
+// pub stream_read_timeout: Duration, /
+// pub http: HttpConfig, /
 // pub stream: bool, /
 // pub use_ldd: bool, /
 // pub send_events: bool, /
 // pub offline: bool, /
+// pub flag_files: Vec<PathBuf>, /
+// pub flag_files_watch: Option<Duration>, /
 // pub store: T, /
 
 type FlagEvaluation = (VariationValue, Option<usize>);
 
+type FlagDetail = (VariationValue, Option<usize>, Explanation);
+
 type ClientResult<T> = Result<T, &'static str>;
 
 pub struct Client<S: Store + 'static> {
     offline: bool,
+    use_ldd: bool,
     event_processor: Option<EventProcessor>,
     event_handle: Option<JoinHandle<()>>,
     update_handle: Option<JoinHandle<()>>,
     store: Arc<S>,
+    status: StatusTracker,
+    changes: FlagChanges,
 }
 
 impl<S: Store> Client<S> {
     pub fn new(key: &str, config: Config<S>) -> Client<S> {
         let store = Arc::new(config.store);
+        let status = StatusTracker::new();
+        let changes = FlagChanges::new();
+
+        // Offline clients get no flags, so there is nothing to wait for. With
+        // use_ldd the status becomes valid once the relay initializes the store
+        if config.offline && !config.use_ldd {
+            status.initialized().set();
+            status.off();
+        }
 
         if config.use_ldd {
             Client {
                 offline: config.offline,
+                use_ldd: true,
                 event_processor: None,
                 event_handle: None,
                 update_handle: None,
                 store: store,
+                status: status,
+                changes: changes,
             }
         } else {
-            let update_handle = if !config.offline {
-                let req = Requestor::new(config.base_uri, key);
-
-                Some(if config.stream {
-                    let stream = Streaming::new(store.clone(), req.into());
-                    stream.run(config.stream_uri.as_str(), key).unwrap()
-                } else {
-                    Polling::new(store.clone(), req.into(), config.poll_interval).run()
-                })
-            } else {
+            // Flags loaded from files are not known to the service, so
+            // events about them are not sent either
+            let send_events = !config.offline && config.send_events && config.flag_files.is_empty();
+
+            let update_handle = if config.offline {
                 None
+            } else if !config.flag_files.is_empty() {
+                Some(
+                    FileDataSource::new(store.clone(), config.flag_files)
+                        .watch(config.flag_files_watch)
+                        .status(status.clone())
+                        .changes(changes.clone())
+                        .run(),
+                )
+            } else {
+                match config.http.client(config.base_uri.as_str(), config.timeout) {
+                    Ok(client) => {
+                        let req = Requestor::with_client(config.base_uri, key, client);
+
+                        Some(if config.stream {
+                            let stream = Streaming::new(store.clone(), req.into())
+                                .read_timeout(config.stream_read_timeout)
+                                .http(config.http.clone())
+                                .status(status.clone())
+                                .changes(changes.clone());
+                            stream.run(config.stream_uri.as_str(), key).unwrap()
+                        } else {
+                            Polling::new(store.clone(), req.into(), config.poll_interval)
+                                .status(status.clone())
+                                .changes(changes.clone())
+                                .run()
+                        })
+                    }
+                    Err(err) => {
+                        error!(
+                            "Invalid HTTP configuration, no flags will be received: {:?}",
+                            err
+                        );
+                        status.off();
+                        None
+                    }
+                }
             };
 
             let (tx, rx) = channel();
+            let server_time = ServerTime::new();
             let e_processor = EventProcessor::new(
-                !config.offline && config.send_events,
+                send_events,
                 config.sampling_interval,
                 tx,
+                server_time.clone(),
             );
 
-            let e_handle = if !config.offline && config.send_events {
-                Some(EventSender::new(config.flush_interval, rx).run(config.events_uri, key))
+            let e_handle = if send_events {
+                Some(
+                    EventSender::new(config.flush_interval, rx, server_time)
+                        .timeout(config.timeout)
+                        .http(config.http)
+                        .run(config.events_uri, key),
+                )
             } else {
                 None
             };
 
             Client {
                 offline: config.offline,
+                use_ldd: false,
                 event_processor: Some(e_processor),
                 event_handle: e_handle,
                 update_handle: update_handle,
                 store: store,
+                status: status,
+                changes: changes,
+            }
+        }
+    }
+
+    /// Creates a client and blocks until its data source has received the
+    /// first set of flags or the timeout elapses. A client that times out is
+    /// still returned and keeps trying in the background
+    pub fn new_with_wait(key: &str, config: Config<S>, timeout: Duration) -> Client<S> {
+        let client = Client::new(key, config);
+
+        if !client.wait_for_initialization(timeout) {
+            warn!(
+                "Client was not initialized within {:?}, evaluations will return defaults until it is",
+                timeout
+            );
+        }
+
+        client
+    }
+
+    pub fn wait_for_initialization(&self, timeout: Duration) -> bool {
+        if !self.use_ldd {
+            return self.status.initialized().wait(timeout);
+        }
+
+        // Nothing signals when the relay populates the store, so poll it
+        let deadline = Instant::now() + timeout;
+        while !self.initialized() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
             }
+
+            thread::sleep(::std::cmp::min(deadline - now, Duration::from_millis(100)));
+        }
+
+        true
+    }
+
+    /// Whether flags have been received. With `use_ldd` this is whether the
+    /// relay has ever initialized the store
+    pub fn initialized(&self) -> bool {
+        if self.status.initialized().is_set() {
+            return true;
         }
+
+        let initialized = self.use_ldd && self.store.initialized();
+        if initialized {
+            self.status.valid();
+        }
+
+        initialized
+    }
+
+    /// With `use_ldd` the status stays initializing until the relay has
+    /// initialized the store
+    pub fn data_source_status(&self) -> DataSourceStatus {
+        if self.use_ldd {
+            self.initialized();
+        }
+
+        self.status.status()
+    }
+
+    /// Whether the persistent store could be reached. While it can not,
+    /// evaluations use the last flags read from it
+    pub fn store_status(&self) -> StoreStatus {
+        self.store.status()
+    }
+
+    /// Receives every change to the data source status from now on
+    pub fn subscribe_data_source_status(&self) -> Receiver<DataSourceStatus> {
+        self.status.subscribe()
+    }
+
+    /// Calls the listener with the key of each flag the data source changes,
+    /// and of every flag depending on it through prerequisites. Listeners run
+    /// on the data source thread
+    pub fn on_flag_change<F>(&self, listener: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.changes.listen(listener);
+    }
+
+    /// Calls the listener with the old and new value whenever a change to the
+    /// flag alters the value served to the given user. Missing flags have no
+    /// value
+    pub fn on_flag_value_change<F>(&self, key: &str, user: User, listener: F)
+    where
+        F: Fn(Option<&VariationValue>, Option<&VariationValue>) + Send + Sync + 'static,
+    {
+        let key = key.to_string();
+        let flag_key = key.clone();
+        let store = self.store.clone();
+        let evaluate = move |user: &User| {
+            store
+                .get(flag_key.as_str())
+                .and_then(|flag| flag.evaluate(user, &store).result.value.ok())
+        };
+        let last = Mutex::new(evaluate(&user));
+
+        self.changes.listen(move |changed| {
+            if changed != key {
+                return;
+            }
+
+            let value = evaluate(&user);
+            let mut last = last.lock().unwrap();
+
+            if *last != value {
+                listener(last.as_ref(), value.as_ref());
+                *last = value;
+            }
+        });
     }
 
-    fn flag(&self, key: &str, user: &User) -> Option<FeatureFlag> {
+    fn flag(&self, key: &str, user: &User) -> Option<Arc<FeatureFlag>> {
         if user.key() == "" {
             warn!("_MESSAGE_USER_KEY_IS_EMPTY");
         }
@@ -101,77 +280,279 @@ impl<S: Store> Client<S> {
         user: &User,
         default: V,
     ) -> FlagEvaluation {
+        let (value, version, _) = self.evaluate_detail(key, user, default);
+        (value, version)
+    }
+
+    /// Evaluates the flag like `evaluate`, also returning the reason for the
+    /// value that was chosen
+    pub fn evaluate_detail<V: Into<VariationValue>>(
+        &self,
+        key: &str,
+        user: &User,
+        default: V,
+    ) -> FlagDetail {
+        let default = default.into();
+
         if self.offline {
-            return (default.into(), None);
+            return (default, None, Explanation::Error(ErrorKind::ClientOffline));
+        }
+
+        if !self.initialized() {
+            let kind = if self.use_ldd {
+                self.status.store_not_initialized();
+                ErrorKind::StoreNotInitialized
+            } else {
+                ErrorKind::ClientNotReady
+            };
+
+            return (default, None, Explanation::Error(kind));
         }
 
         if let Some(flag) = self.flag(key, user) {
-            self.eval(&flag, user)
-                .unwrap_or((default.into(), Some(flag.version())))
+            let (result, explanation) = self.eval(&flag, user, &default);
+            let (value, version) = result.unwrap_or((default, Some(flag.version())));
+
+            (value, version, explanation)
         } else {
-            (default.into(), None)
+            (default, None, Explanation::Error(ErrorKind::FlagNotFound))
         }
     }
 
-    fn eval(&self, flag: &FeatureFlag, user: &User) -> ClientResult<FlagEvaluation> {
+    fn eval(
+        &self,
+        flag: &FeatureFlag,
+        user: &User,
+        default: &VariationValue,
+    ) -> (ClientResult<FlagEvaluation>, Explanation) {
         let Eval { result, events } = flag.evaluate(user, &self.store);
 
         if let Some(ref p) = self.event_processor {
             for event in events {
                 p.push(event);
             }
+
+            let event = FeatureRequestEvent::new(
+                flag.key(),
+                user,
+                result.value.clone().ok(),
+                Some(default.clone()),
+                flag.version(),
+                None,
+            )
+            .tracking(
+                flag.tracks(&result.explanation),
+                flag.debug_events_until_date(),
+            )
+            .explained(result.explanation);
+            p.push(Event::FeatureRequest(event));
         }
 
-        result
+        let value = result
             .value
             .map(|val| (val, Some(flag.version())))
-            .map_err(|_| "Failed to eval")
+            .map_err(|_| "Failed to eval");
+
+        (value, result.explanation)
     }
 
     pub fn bool_variation(&self, key: &str, user: &User, default: bool) -> bool {
-        match self.variation(key, user) {
+        match self.variation(key, user, default.into()) {
             Some(VariationValue::Boolean(val)) => val,
             _ => default,
         }
     }
 
     pub fn int_variation(&self, key: &str, user: &User, default: i64) -> i64 {
-        match self.variation(key, user) {
+        match self.variation(key, user, default.into()) {
             Some(VariationValue::Integer(val)) => val,
             _ => default,
         }
     }
 
     pub fn float_variation(&self, key: &str, user: &User, default: f64) -> f64 {
-        match self.variation(key, user) {
+        match self.variation(key, user, default.into()) {
             Some(VariationValue::Float(val)) => val,
             _ => default,
         }
     }
 
     pub fn string_variation<V: Into<String>>(&self, key: &str, user: &User, default: V) -> String {
-        match self.variation(key, user) {
+        let default = default.into();
+
+        match self.variation(key, user, default.clone().into()) {
             Some(VariationValue::String(val)) => val,
-            _ => default.into(),
+            _ => default,
         }
     }
 
-    fn variation(&self, key: &str, user: &User) -> Option<VariationValue> {
-        self.flag(key, user)
-            .and_then(|flag| self.eval(&flag, user).map(|(variation, _)| variation).ok())
+    fn variation(&self, key: &str, user: &User, default: VariationValue) -> Option<VariationValue> {
+        if !self.initialized() {
+            return None;
+        }
+
+        self.flag(key, user).and_then(|flag| {
+            self.eval(&flag, user, &default)
+                .0
+                .map(|(variation, _)| variation)
+                .ok()
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
     use client::Client;
     use config::{Config, ConfigBuilder};
+    use feature_flag::{ErrorKind, Explanation, FeatureFlag, VariationOrRollOut, VariationValue};
+    use mem_store::MemStore;
+    use read_only::ReadOnlyStore;
+    use status::DataSourceStatus;
+    use store::Store;
+    use user::UserBuilder;
+
+    #[test]
+    fn test_not_ready_before_initialization() {
+        let config = ConfigBuilder::new()
+            .stream_uri("http://127.0.0.1:1")
+            .send_events(false)
+            .build();
+        let client = Client::new_with_wait("abcdefg", config, Duration::from_millis(20));
+        let user = UserBuilder::new("user").build();
+
+        assert!(!client.initialized());
+        assert_eq!(client.data_source_status(), DataSourceStatus::Initializing);
+        assert_eq!(
+            client.evaluate_detail("flag", &user, true),
+            (
+                VariationValue::Boolean(true),
+                None,
+                Explanation::Error(ErrorKind::ClientNotReady),
+            )
+        );
+    }
+
+    #[test]
+    fn test_use_ldd_is_initialized_by_store() {
+        let store = MemStore::new();
+        store.init(HashMap::new()).unwrap();
+
+        let config = ConfigBuilder::new()
+            .use_ldd(true)
+            .store(ReadOnlyStore::new(store))
+            .build();
+        let client = Client::new("abcdefg", config);
+        let user = UserBuilder::new("user").build();
+
+        assert!(client.wait_for_initialization(Duration::from_millis(0)));
+        assert_eq!(client.data_source_status(), DataSourceStatus::Valid);
+        assert_eq!(
+            client.evaluate_detail("flag", &user, true).2,
+            Explanation::Error(ErrorKind::FlagNotFound)
+        );
+    }
+
+    #[test]
+    fn test_use_ldd_reports_uninitialized_store() {
+        let config = ConfigBuilder::new().use_ldd(true).build();
+        let client = Client::new("abcdefg", config);
+        let user = UserBuilder::new("user").build();
+
+        assert!(!client.wait_for_initialization(Duration::from_millis(20)));
+        assert_eq!(client.data_source_status(), DataSourceStatus::Initializing);
+        assert_eq!(
+            client.evaluate_detail("flag", &user, true),
+            (
+                VariationValue::Boolean(true),
+                None,
+                Explanation::Error(ErrorKind::StoreNotInitialized),
+            )
+        );
+    }
+
+    #[test]
+    fn test_flag_file_data_source() {
+        let path = ::std::env::temp_dir().join(format!(
+            "dark_client_flags_{}.json",
+            ::std::process::id()
+        ));
+        ::std::fs::write(&path, r#"{"flagValues": {"flag": true}}"#).unwrap();
+
+        let config = ConfigBuilder::new().flag_file(path).build();
+        let client = Client::new_with_wait("abcdefg", config, Duration::from_secs(1));
+        let user = UserBuilder::new("user").build();
+
+        assert!(client.initialized());
+        assert!(client.bool_variation("flag", &user, false));
+        assert!(client.event_handle.is_none());
+    }
+
+    #[test]
+    fn test_value_change_listener() {
+        let flag = |version, variation| {
+            FeatureFlag::new(
+                "flag".into(),
+                version,
+                true,
+                vec![],
+                "salt".into(),
+                "sel".into(),
+                vec![],
+                vec![],
+                VariationOrRollOut::Variation(variation),
+                Some(0),
+                vec![false.into(), true.into()],
+                false,
+            )
+        };
+
+        let config = ConfigBuilder::new().use_ldd(true).build();
+        let client = Client::new("abcdefg", config);
+        client.store.upsert("flag", &flag(1, 0)).unwrap();
+
+        let values = Arc::new(Mutex::new(vec![]));
+        let v = values.clone();
+        client.on_flag_value_change("flag", UserBuilder::new("user").build(), move |old, new| {
+            v.lock().unwrap().push((old.cloned(), new.cloned()))
+        });
+
+        client
+            .changes
+            .upsert(&*client.store, "flag", &flag(2, 0))
+            .unwrap();
+        client
+            .changes
+            .upsert(&*client.store, "flag", &flag(3, 1))
+            .unwrap();
+        client.changes.delete(&*client.store, "flag", 4).unwrap();
+
+        assert_eq!(
+            *values.lock().unwrap(),
+            vec![
+                (Some(false.into()), Some(true.into())),
+                (Some(true.into()), None),
+            ]
+        );
+    }
 
     #[test]
     fn test_offline_returns_default() {
         let config = ConfigBuilder::new().offline(true).build();
         let client = Client::new("abcdefg", config);
-        unimplemented!()
+        let user = UserBuilder::new("user").build();
+
+        assert_eq!(
+            client.evaluate_detail("flag", &user, true),
+            (
+                VariationValue::Boolean(true),
+                None,
+                Explanation::Error(ErrorKind::ClientOffline),
+            )
+        );
     }
 
     #[test]
@@ -0,0 +1,187 @@
+//! Checks that any `Store` behaves like the ones in this crate. Each check
+//! takes a function creating the store under test, which is emptied with
+//! `init` before use, and panics when the store misbehaves.
+//!
+//! `store_conformance_tests!` generates a test for every check:
+//!
+//! ```ignore
+//! #[cfg(test)]
+//! mod tests {
+//!     store_conformance_tests!(|| MyStore::open("localhost"));
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+use feature_flag::{FeatureFlag, VariationOrRollOut, VariationValue};
+use store::{Store, StoreError};
+
+/// Generates a test for every conformance check, creating stores with the
+/// given function. Attributes after the function are added to every test,
+/// e.g. `#[ignore]` for backends needing a server
+#[macro_export]
+macro_rules! store_conformance_tests {
+    ($new:expr $(, #[$attr:meta])*) => {
+        #[test]
+        $(#[$attr])*
+        fn conformance_versioning() {
+            $crate::conformance::versioning($new);
+        }
+
+        #[test]
+        $(#[$attr])*
+        fn conformance_tombstones() {
+            $crate::conformance::tombstones($new);
+        }
+
+        #[test]
+        $(#[$attr])*
+        fn conformance_init_replaces_all_flags() {
+            $crate::conformance::init_replaces_all_flags($new);
+        }
+
+        #[test]
+        $(#[$attr])*
+        fn conformance_get_all_skips_deleted() {
+            $crate::conformance::get_all_skips_deleted($new);
+        }
+
+        #[test]
+        $(#[$attr])*
+        fn conformance_concurrent_writers() {
+            $crate::conformance::concurrent_writers($new);
+        }
+    };
+}
+
+/// A flag serving its first variation to everyone
+pub fn flag(key: &str, version: usize, deleted: bool) -> FeatureFlag {
+    FeatureFlag::new(
+        key.into(),
+        version,
+        true,
+        vec![],
+        "".into(),
+        "".into(),
+        vec![],
+        vec![],
+        VariationOrRollOut::Variation(0),
+        None,
+        vec![VariationValue::Integer(0), VariationValue::Integer(1)],
+        deleted,
+    )
+}
+
+fn empty<S: Store, F: Fn() -> S>(new: F) -> S {
+    let store = new();
+    store
+        .init(HashMap::new())
+        .expect("store could not be emptied");
+    store
+}
+
+/// Only newer versions replace a flag
+pub fn versioning<S: Store, F: Fn() -> S>(new: F) {
+    let store = empty(new);
+
+    assert_eq!(store.upsert("f1", &flag("f1", 5, false)), Ok(()));
+    assert_eq!(
+        store.upsert("f1", &flag("f1", 3, false)),
+        Err(StoreError::NewerVersionFound)
+    );
+    assert_eq!(
+        store.upsert("f1", &flag("f1", 5, false)),
+        Err(StoreError::NewerVersionFound)
+    );
+    assert_eq!(store.get("f1").map(|f| f.version()), Some(5));
+
+    assert_eq!(store.upsert("f1", &flag("f1", 6, false)), Ok(()));
+    assert_eq!(store.get("f1").map(|f| f.version()), Some(6));
+}
+
+/// Deleted flags are hidden, and their version still rejects older updates
+pub fn tombstones<S: Store, F: Fn() -> S>(new: F) {
+    let store = empty(new);
+
+    assert_eq!(store.delete("f1", 1), Err(StoreError::NotFound));
+
+    store.upsert("f1", &flag("f1", 5, false)).unwrap();
+    assert_eq!(store.delete("f1", 3), Err(StoreError::NewerVersionFound));
+    assert!(store.get("f1").is_some());
+
+    assert_eq!(store.delete("f1", 6), Ok(()));
+    assert!(store.get("f1").is_none());
+
+    assert_eq!(
+        store.upsert("f1", &flag("f1", 4, false)),
+        Err(StoreError::NewerVersionFound)
+    );
+    assert!(store.get("f1").is_none());
+
+    assert_eq!(store.upsert("f1", &flag("f1", 7, false)), Ok(()));
+    assert_eq!(store.get("f1").map(|f| f.version()), Some(7));
+}
+
+/// `init` removes every flag that is not part of the new set, tombstones
+/// included
+pub fn init_replaces_all_flags<S: Store, F: Fn() -> S>(new: F) {
+    let store = empty(new);
+    assert!(store.initialized());
+
+    store.upsert("f1", &flag("f1", 1, false)).unwrap();
+    store.upsert("f4", &flag("f4", 1, false)).unwrap();
+    store.delete("f4", 5).unwrap();
+
+    let mut flags = HashMap::new();
+    flags.insert("f2".to_string(), flag("f2", 1, false));
+    flags.insert("f3".to_string(), flag("f3", 1, true));
+    store.init(flags).unwrap();
+
+    assert!(store.get("f1").is_none());
+    assert!(store.get("f2").is_some());
+    assert!(store.get("f3").is_none());
+    assert_eq!(
+        store.get_all().unwrap().keys().collect::<Vec<_>>(),
+        vec!["f2"]
+    );
+
+    assert_eq!(store.upsert("f4", &flag("f4", 2, false)), Ok(()));
+}
+
+/// `get_all` returns every flag except deleted ones
+pub fn get_all_skips_deleted<S: Store, F: Fn() -> S>(new: F) {
+    let store = empty(new);
+
+    store.upsert("f1", &flag("f1", 1, false)).unwrap();
+    store.upsert("f2", &flag("f2", 1, false)).unwrap();
+    store.upsert("f3", &flag("f3", 1, true)).unwrap();
+    store.delete("f2", 2).unwrap();
+
+    let all = store.get_all().unwrap();
+    assert_eq!(all.len(), 1);
+    assert_eq!(all.get("f1").map(|f| f.version()), Some(1));
+}
+
+/// Racing writers leave the highest version in place, every other writer
+/// either succeeds or finds a newer version
+pub fn concurrent_writers<S: Store + 'static, F: Fn() -> S>(new: F) {
+    let store = Arc::new(empty(new));
+
+    let handles = (1..21)
+        .map(|version| {
+            let store = store.clone();
+            thread::spawn(move || (version, store.upsert("f1", &flag("f1", version, false))))
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        match handle.join().unwrap() {
+            (_, Ok(())) | (_, Err(StoreError::NewerVersionFound)) => (),
+            (version, Err(err)) => panic!("version {} failed with {:?}", version, err),
+        }
+    }
+
+    assert_eq!(store.get("f1").map(|f| f.version()), Some(20));
+}
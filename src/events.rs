@@ -1,15 +1,16 @@
 use chrono::Utc;
-use reqwest::Client;
-use reqwest::header::{Authorization, ContentType, Headers, UserAgent};
+use reqwest::header::{Authorization, ContentType, Date, Headers};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json;
 
+use std::sync::{Arc, RwLock};
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use feature_flag::VariationValue;
+use feature_flag::{Explanation, VariationValue};
+use http::HttpConfig;
 use user::User;
-use VERSION;
 
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
@@ -22,9 +23,10 @@ pub enum EventError {
     FailedToParseEndpoint,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Kind {
     FeatureRequestEvent,
+    DebugEvent,
     CustomEvent,
     IdentifyEvent,
 }
@@ -36,6 +38,7 @@ impl Serialize for Kind {
     {
         serializer.serialize_str(match *self {
             Kind::FeatureRequestEvent => "feature",
+            Kind::DebugEvent => "debug",
             Kind::CustomEvent => "custom",
             Kind::IdentifyEvent => "indentify",
         })
@@ -50,6 +53,7 @@ impl<'de> Deserialize<'de> for Kind {
         let s = String::deserialize(deserializer)?;
         match s.as_str() {
             "feature" => Ok(Kind::FeatureRequestEvent),
+            "debug" => Ok(Kind::DebugEvent),
             "custom" => Ok(Kind::CustomEvent),
             "indentify" => Ok(Kind::IdentifyEvent),
             _ => Err(::serde::de::Error::custom("Invalid event kind")),
@@ -57,7 +61,7 @@ impl<'de> Deserialize<'de> for Kind {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FeatureRequestEvent {
     #[serde(rename = "creationDate")]
     creation_date: i64,
@@ -71,6 +75,12 @@ pub struct FeatureRequestEvent {
     version: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     prereq_of: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    reason: Option<Explanation>,
+    #[serde(skip)]
+    track_events: bool,
+    #[serde(skip)]
+    debug_events_until_date: Option<i64>,
 }
 
 impl<'a> FeatureRequestEvent {
@@ -91,6 +101,71 @@ impl<'a> FeatureRequestEvent {
             default: default,
             version: version,
             prereq_of: prereq_of,
+            reason: None,
+            track_events: false,
+            debug_events_until_date: None,
+        }
+    }
+
+    pub fn tracking(
+        mut self,
+        track_events: bool,
+        debug_events_until_date: Option<i64>,
+    ) -> FeatureRequestEvent {
+        self.track_events = track_events;
+        self.debug_events_until_date = debug_events_until_date;
+        self
+    }
+
+    // Reasons are only reported for users placed into an experiment
+    pub fn explained(mut self, explanation: Explanation) -> FeatureRequestEvent {
+        if explanation.in_experiment() {
+            self.reason = Some(explanation);
+        }
+
+        self
+    }
+
+    pub fn kind(&self) -> &Kind {
+        &self.kind
+    }
+
+    // Debugging stays active until the flag's debug date has passed by both
+    // the local clock and the last time reported by the server
+    fn debugging(&self, server_time: i64) -> bool {
+        self.debug_events_until_date.map_or(false, |until| {
+            until > server_time && until > Utc::now().timestamp() * 1000
+        })
+    }
+
+    fn to_debug(&self) -> FeatureRequestEvent {
+        let mut debug = self.clone();
+        debug.kind = Kind::DebugEvent;
+        debug
+    }
+}
+
+/// The most recent time, in milliseconds, reported by the events service via
+/// response Date headers
+#[derive(Clone, Debug, Default)]
+pub struct ServerTime {
+    millis: Arc<RwLock<i64>>,
+}
+
+impl ServerTime {
+    pub fn new() -> ServerTime {
+        ServerTime::default()
+    }
+
+    pub fn get(&self) -> i64 {
+        self.millis.read().map(|millis| *millis).unwrap_or(0)
+    }
+
+    pub fn update(&self, time: SystemTime) {
+        if let Ok(since) = time.duration_since(UNIX_EPOCH) {
+            if let Ok(mut millis) = self.millis.write() {
+                *millis = since.as_secs() as i64 * 1000 + since.subsec_nanos() as i64 / 1000000;
+            }
         }
     }
 }
@@ -99,20 +174,37 @@ pub struct EventProcessor {
     send_events: bool,
     sampling_interval: i64,
     channel: Sender<Event>,
+    server_time: ServerTime,
 }
 
 impl EventProcessor {
-    pub fn new(active: bool, sampling_interval: i64, channel: Sender<Event>) -> EventProcessor {
+    pub fn new(
+        active: bool,
+        sampling_interval: i64,
+        channel: Sender<Event>,
+        server_time: ServerTime,
+    ) -> EventProcessor {
         EventProcessor {
             send_events: active,
             sampling_interval: sampling_interval,
             channel: channel,
+            server_time: server_time,
         }
     }
 
     pub fn push(&self, e: Event) {
         if self.send_events && self.sampling_interval == 0 {
-            self.channel.send(e);
+            match e {
+                Event::FeatureRequest(event) => {
+                    if event.debugging(self.server_time.get()) {
+                        self.channel.send(Event::FeatureRequest(event.to_debug()));
+                    }
+
+                    if event.track_events {
+                        self.channel.send(Event::FeatureRequest(event));
+                    }
+                }
+            }
         }
     }
 }
@@ -120,16 +212,37 @@ impl EventProcessor {
 pub struct EventSender {
     flush_interval: i64,
     stream: Receiver<Event>,
+    server_time: ServerTime,
+    timeout: Duration,
+    http: HttpConfig,
 }
 
 impl EventSender {
-    pub fn new(flush_interval: i64, stream: Receiver<Event>) -> EventSender {
+    pub fn new(
+        flush_interval: i64,
+        stream: Receiver<Event>,
+        server_time: ServerTime,
+    ) -> EventSender {
         EventSender {
             flush_interval: flush_interval,
             stream: stream,
+            server_time: server_time,
+            timeout: Duration::from_secs(10),
+            http: HttpConfig::default(),
         }
     }
 
+    /// Bounds connecting to the events service and each read or write
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn http(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
     pub fn run<S: Into<String>, T: Into<String>>(
         self,
         endpoint: S,
@@ -139,7 +252,16 @@ impl EventSender {
         let e = endpoint.into();
 
         thread::spawn(move || {
-            let client = Client::new();
+            let client = match self.http.client(e.as_str(), self.timeout) {
+                Ok(client) => client,
+                Err(err) => {
+                    error!(
+                        "Failed to create events client, no events will be sent: {:?}",
+                        err
+                    );
+                    return;
+                }
+            };
 
             let flush_interval = self.flush_interval;
             let mut start = Utc::now().timestamp();
@@ -148,7 +270,6 @@ impl EventSender {
             let mut headers = Headers::new();
             headers.set(Authorization(k.clone()));
             headers.set(ContentType::json());
-            headers.set(UserAgent::new("RustTest/".to_string() + VERSION));
 
             loop {
                 if let Ok(event) = self.stream.try_recv() {
@@ -158,7 +279,24 @@ impl EventSender {
                 if start + flush_interval < Utc::now().timestamp() {
                     if batch.len() > 0 {
                         if let Ok(data) = serde_json::to_string(&batch) {
-                            client.post(e.as_str()).headers(headers.clone()).body(data);
+                            let res = client
+                                .post(e.as_str())
+                                .headers(headers.clone())
+                                .body(data)
+                                .send();
+
+                            match res {
+                                Ok(res) => {
+                                    if let Some(date) = res.headers().get::<Date>() {
+                                        self.server_time.update(date.0.into());
+                                    }
+
+                                    if !res.status().is_success() {
+                                        warn!("Events post failed with status {}", res.status());
+                                    }
+                                }
+                                Err(err) => warn!("Failed to post events: {}", err),
+                            }
                         }
 
                         // Always reset, if a batch fails to serialize once,
@@ -186,23 +324,70 @@ mod tests {
 
         let (tx, rx) = channel();
 
-        let processor = EventProcessor::new(true, 0, tx);
-        let sender = EventSender::new(0, rx);
+        let time = ServerTime::new();
+        let processor = EventProcessor::new(true, 0, tx, time.clone());
+        let sender = EventSender::new(0, rx, time);
 
         let handle = sender.run("https://0.0.0.0", "");
 
-        processor.push(Event::FeatureRequest(FeatureRequestEvent::new(
-            "level-1",
-            &u,
-            None,
-            None,
-            1,
-            None,
-        )));
+        processor.push(Event::FeatureRequest(
+            FeatureRequestEvent::new("level-1", &u, None, None, 1, None).tracking(true, None),
+        ));
 
         // Wait to make sure the sender ticks
         ::std::thread::sleep(::std::time::Duration::new(2, 0));
 
         drop(handle);
     }
+
+    fn received(track_events: bool, debug_until: Option<i64>, server_time: i64) -> Vec<Kind> {
+        let u = UserBuilder::new("user_key").build();
+        let (tx, rx) = channel();
+
+        let time = ServerTime::new();
+        time.update(UNIX_EPOCH + ::std::time::Duration::from_millis(server_time as u64));
+
+        let processor = EventProcessor::new(true, 0, tx, time);
+        processor.push(Event::FeatureRequest(
+            FeatureRequestEvent::new("level-1", &u, None, None, 1, None)
+                .tracking(track_events, debug_until),
+        ));
+
+        rx.try_iter()
+            .map(|e| match e {
+                Event::FeatureRequest(event) => event.kind().clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_untracked_event_is_dropped() {
+        assert_eq!(received(false, None, 0), vec![]);
+    }
+
+    #[test]
+    fn test_tracked_event_is_sent() {
+        assert_eq!(received(true, None, 0), vec![Kind::FeatureRequestEvent]);
+    }
+
+    #[test]
+    fn test_debug_event_sent_until_date() {
+        let future = (Utc::now().timestamp() + 3600) * 1000;
+        let past = (Utc::now().timestamp() - 3600) * 1000;
+
+        assert_eq!(received(false, Some(future), 0), vec![Kind::DebugEvent]);
+        assert_eq!(
+            received(true, Some(future), 0),
+            vec![Kind::DebugEvent, Kind::FeatureRequestEvent]
+        );
+        assert_eq!(received(false, Some(past), 0), vec![]);
+    }
+
+    #[test]
+    fn test_debug_event_respects_server_time() {
+        let future = (Utc::now().timestamp() + 3600) * 1000;
+        let server_ahead = (Utc::now().timestamp() + 7200) * 1000;
+
+        assert_eq!(received(false, Some(future), server_ahead), vec![]);
+    }
 }
@@ -39,10 +39,13 @@ mod tests {
     fn test_polling() {
         let store = Arc::new(MemStore::new());
 
-        let req = Arc::new(Requestor::new(
-            "https://app.launchdarkly.com",
-            "sdk-00617963-388b-4ad4-b3c0-a49d1027ab7e",
-        ));
+        let req = Arc::new(
+            Requestor::new(
+                "https://app.launchdarkly.com",
+                "sdk-00617963-388b-4ad4-b3c0-a49d1027ab7e",
+            )
+            .unwrap(),
+        );
 
         let poll = Polling::new(store.clone(), req.clone(), 5);
 
@@ -57,10 +60,13 @@ mod tests {
     fn test_streaming() {
         let store = Arc::new(MemStore::new());
 
-        let req = Arc::new(Requestor::new(
-            "https://app.launchdarkly.com",
-            "sdk-00617963-388b-4ad4-b3c0-a49d1027ab7e",
-        ));
+        let req = Arc::new(
+            Requestor::new(
+                "https://app.launchdarkly.com",
+                "sdk-00617963-388b-4ad4-b3c0-a49d1027ab7e",
+            )
+            .unwrap(),
+        );
 
         let stream = Streaming::new(store.clone(), req.clone());
 
@@ -1,5 +1,11 @@
-use redis::{ErrorKind, FromRedisValue, RedisResult, ToRedisArgs, Value as RedisValue};
+use redis::{
+    ErrorKind as RedisErrorKind, FromRedisValue, RedisResult, RedisWrite, ToRedisArgs,
+    Value as RedisValue,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::ser::SerializeMap;
 use serde_json;
+use serde_json::Value as JsonValue;
 
 use std::sync::Arc;
 
@@ -17,6 +23,7 @@ pub enum VariationValue {
     Integer(i64),
     Float(f64),
     String(String),
+    Json(JsonValue),
 }
 
 impl From<bool> for VariationValue {
@@ -53,21 +60,40 @@ pub enum FlagError {
     InvalidVariationIndex,
 }
 
+// The service omits fields freely, so everything other than the key and
+// version falls back to a default when absent
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct FeatureFlag {
     key: String,
     version: usize,
+    #[serde(default)]
     on: bool,
+    #[serde(default)]
     prerequisites: Vec<Prerequisite>,
+    #[serde(default)]
     salt: String,
+    #[serde(default)]
     sel: String,
+    #[serde(default)]
     targets: Vec<Target>,
+    #[serde(default)]
     rules: Vec<Rule>,
+    #[serde(default)]
     fallthrough: VariationOrRollOut,
-    #[serde(rename = "offVariation")]
     off_variation: Option<usize>,
+    #[serde(default)]
     variations: Vec<VariationValue>,
+    #[serde(default)]
     deleted: bool,
+    #[serde(default)]
+    track_events: bool,
+    #[serde(default)]
+    track_events_fallthrough: bool,
+    #[serde(default)]
+    client_side: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug_events_until_date: Option<i64>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -78,14 +104,63 @@ pub struct Prerequisite {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Target {
+    #[serde(default)]
     pub values: Vec<String>,
     pub variation: Option<Variation>,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Rule {
+    pub id: Option<String>,
     variation_or_rollout: VariationOrRollOut,
     pub clauses: Vec<Clause>,
+    pub track_events: bool,
+}
+
+// Rules carry their variation or rollout inline alongside the clauses
+#[derive(Deserialize)]
+struct RawRule {
+    id: Option<String>,
+    variation: Option<Variation>,
+    rollout: Option<Rollout>,
+    #[serde(default)]
+    clauses: Vec<Clause>,
+    #[serde(default, rename = "trackEvents")]
+    track_events: bool,
+}
+
+impl Serialize for Rule {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+
+        if let Some(ref id) = self.id {
+            map.serialize_entry("id", id)?;
+        }
+
+        self.variation_or_rollout.serialize_inline(&mut map)?;
+        map.serialize_entry("clauses", &self.clauses)?;
+        map.serialize_entry("trackEvents", &self.track_events)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawRule::deserialize(deserializer)?;
+
+        Ok(Rule {
+            id: raw.id,
+            variation_or_rollout: VariationOrRollOut::from_parts(raw.variation, raw.rollout),
+            clauses: raw.clauses,
+            track_events: raw.track_events,
+        })
+    }
 }
 
 impl Rule {
@@ -94,7 +169,7 @@ impl Rule {
         user: &User,
         key: &str,
         salt: &str,
-    ) -> Option<Variation> {
+    ) -> Option<(Variation, bool)> {
         self.variation_or_rollout
             .variation_index_for_user(user, key, salt)
     }
@@ -106,26 +181,90 @@ impl Rule {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum VariationOrRollOut {
-    #[serde(rename = "rollout")] Rollout(Rollout),
-    #[serde(rename = "variation")] Variation(Variation),
+    Rollout(Rollout),
+    Variation(Variation),
+}
+
+#[derive(Deserialize)]
+struct RawVariationOrRollOut {
+    variation: Option<Variation>,
+    rollout: Option<Rollout>,
+}
+
+// A missing variation and rollout is treated as an empty rollout, which fails
+// to produce a variation index when evaluated
+impl Default for VariationOrRollOut {
+    fn default() -> VariationOrRollOut {
+        VariationOrRollOut::Rollout(Rollout {
+            weighted_variations: vec![],
+            bucket_by: None,
+            kind: None,
+            seed: None,
+        })
+    }
+}
+
+impl Serialize for VariationOrRollOut {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(1))?;
+        self.serialize_inline(&mut map)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for VariationOrRollOut {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawVariationOrRollOut::deserialize(deserializer)?;
+        Ok(VariationOrRollOut::from_parts(raw.variation, raw.rollout))
+    }
 }
 
 impl VariationOrRollOut {
+    fn from_parts(variation: Option<Variation>, rollout: Option<Rollout>) -> VariationOrRollOut {
+        match (variation, rollout) {
+            (Some(variation), _) => VariationOrRollOut::Variation(variation),
+            (None, Some(rollout)) => VariationOrRollOut::Rollout(rollout),
+            (None, None) => VariationOrRollOut::default(),
+        }
+    }
+
+    fn serialize_inline<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        match *self {
+            VariationOrRollOut::Rollout(ref rollout) => map.serialize_entry("rollout", rollout),
+            VariationOrRollOut::Variation(ref variation) => {
+                map.serialize_entry("variation", variation)
+            }
+        }
+    }
+
+    /// Returns the variation index for the user along with whether the user
+    /// was placed into an experiment
     pub fn variation_index_for_user(
         &self,
         user: &User,
         key: &str,
         salt: &str,
-    ) -> Option<Variation> {
+    ) -> Option<(Variation, bool)> {
         match *self {
             VariationOrRollOut::Rollout(ref rollout) => {
                 if rollout.weighted_variations.len() == 0 {
                     None
                 } else {
-                    let by = rollout.bucket_by.as_ref().map_or("key", |v| v.as_str());
-                    let bucket = user.bucket(key, by, salt);
+                    // Experiments always bucket by key
+                    let by = if rollout.is_experiment() {
+                        "key"
+                    } else {
+                        rollout.bucket_by.as_ref().map_or("key", |v| v.as_str())
+                    };
+                    let bucket = user.bucket(key, by, salt, rollout.seed);
 
                     let mut sum: f64 = 0.0;
 
@@ -133,30 +272,52 @@ impl VariationOrRollOut {
                         sum = sum + weighted_var.weight as f64 / 100000.0;
 
                         if bucket < sum {
-                            return Some(weighted_var.variation);
+                            return Some((
+                                weighted_var.variation,
+                                rollout.is_experiment() && !weighted_var.untracked,
+                            ));
                         }
                     }
 
                     None
                 }
             }
-            VariationOrRollOut::Variation(variation) => Some(variation),
+            VariationOrRollOut::Variation(variation) => Some((variation, false)),
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RolloutKind {
+    Rollout,
+    Experiment,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Rollout {
-    #[serde(rename = "variations")]
+    #[serde(rename = "variations", default)]
     pub weighted_variations: Vec<WeightedVariation>,
     #[serde(rename = "bucketBy")]
     pub bucket_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<RolloutKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+impl Rollout {
+    pub fn is_experiment(&self) -> bool {
+        self.kind == Some(RolloutKind::Experiment)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct WeightedVariation {
     pub variation: Variation,
     pub weight: usize,
+    #[serde(default)]
+    pub untracked: bool,
 }
 
 #[derive(Debug)]
@@ -176,22 +337,90 @@ pub struct IndexResult {
     pub explanation: Explanation,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+// Explanations refer to the prerequisite, rule or target of the evaluated flag
+// by index so that evaluation does not need to clone them
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Explanation {
-    Prerequisite(Prerequisite),
-    Rule(Rule),
-    Target(Target),
-    VariationOrRollOut(VariationOrRollOut),
+    Prerequisite(usize),
+    Rule { index: usize, in_experiment: bool },
+    Target(usize),
+    Fallthrough { in_experiment: bool },
+    Error(ErrorKind),
+}
+
+/// Why an evaluation could not be performed and the default was returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    ClientNotReady,
+    ClientOffline,
+    FlagNotFound,
+    StoreNotInitialized,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorKind::ClientNotReady => "CLIENT_NOT_READY",
+            ErrorKind::ClientOffline => "CLIENT_OFFLINE",
+            ErrorKind::FlagNotFound => "FLAG_NOT_FOUND",
+            ErrorKind::StoreNotInitialized => "STORE_NOT_INITIALIZED",
+        }
+    }
 }
 
 impl Explanation {
     pub fn kind(&self) -> &'static str {
         match *self {
             Explanation::Prerequisite(_) => "prerequisite",
-            Explanation::Rule(_) => "rule",
+            Explanation::Rule { .. } => "rule",
             Explanation::Target(_) => "target",
-            Explanation::VariationOrRollOut(_) => "fallthrough",
+            Explanation::Fallthrough { .. } => "fallthrough",
+            Explanation::Error(_) => "error",
+        }
+    }
+
+    pub fn in_experiment(&self) -> bool {
+        match *self {
+            Explanation::Rule { in_experiment, .. } => in_experiment,
+            Explanation::Fallthrough { in_experiment } => in_experiment,
+            _ => false,
+        }
+    }
+}
+
+// Serialized in the reason format used by the events service
+impl Serialize for Explanation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+
+        match *self {
+            Explanation::Prerequisite(_) => {
+                map.serialize_entry("kind", "PREREQUISITE_FAILED")?;
+            }
+            Explanation::Rule { index, .. } => {
+                map.serialize_entry("kind", "RULE_MATCH")?;
+                map.serialize_entry("ruleIndex", &index)?;
+            }
+            Explanation::Target(_) => {
+                map.serialize_entry("kind", "TARGET_MATCH")?;
+            }
+            Explanation::Fallthrough { .. } => {
+                map.serialize_entry("kind", "FALLTHROUGH")?;
+            }
+            Explanation::Error(error) => {
+                map.serialize_entry("kind", "ERROR")?;
+                map.serialize_entry("errorKind", error.as_str())?;
+            }
+        }
+
+        if self.in_experiment() {
+            map.serialize_entry("inExperiment", &true)?;
         }
+
+        map.end()
     }
 }
 
@@ -223,6 +452,10 @@ impl FeatureFlag {
             off_variation: off_variation,
             variations: variations,
             deleted: deleted,
+            track_events: false,
+            track_events_fallthrough: false,
+            client_side: false,
+            debug_events_until_date: None,
         }
     }
 
@@ -245,7 +478,7 @@ impl FeatureFlag {
         events: &mut Vec<FeatureRequestEvent>,
     ) -> VariationResult {
         let mut failed_prereq = None;
-        for prereq in self.prerequisites.iter() {
+        for (i, prereq) in self.prerequisites.iter().enumerate() {
             if failed_prereq.is_none() {
                 failed_prereq = if let Some(p_flag) = store.get(prereq.key.as_str()) {
                     if p_flag.on() {
@@ -261,7 +494,12 @@ impl FeatureFlag {
                             None,
                             p_flag.version(),
                             Some(self.key().into()),
-                        );
+                        )
+                        .tracking(
+                            p_flag.tracks(&p_flag_eval.explanation),
+                            p_flag.debug_events_until_date(),
+                        )
+                        .explained(p_flag_eval.explanation);
                         events.push(event);
 
                         if let Ok(val) = p_flag_eval.value {
@@ -269,19 +507,19 @@ impl FeatureFlag {
                                 if val == var {
                                     None
                                 } else {
-                                    Some(prereq)
+                                    Some(i)
                                 }
                             } else {
-                                Some(prereq)
+                                Some(i)
                             }
                         } else {
-                            Some(prereq)
+                            Some(i)
                         }
                     } else {
-                        Some(prereq)
+                        Some(i)
                     }
                 } else {
-                    Some(prereq)
+                    Some(i)
                 }
             }
         }
@@ -289,7 +527,7 @@ impl FeatureFlag {
         match failed_prereq {
             Some(failure) => VariationResult {
                 value: Err(FlagError::FailedToSatisfyPrereq),
-                explanation: Explanation::Prerequisite(failure.clone()),
+                explanation: Explanation::Prerequisite(failure),
             },
             None => {
                 let index = self.eval_index(user);
@@ -306,32 +544,39 @@ impl FeatureFlag {
     }
 
     pub fn eval_index(&self, user: &User) -> IndexResult {
-        for target in self.targets.iter() {
+        for (i, target) in self.targets.iter().enumerate() {
             for value in target.values.iter() {
                 if value == user.key() {
                     return IndexResult {
                         value: target.variation,
-                        explanation: Explanation::Target(target.clone()),
+                        explanation: Explanation::Target(i),
                     };
                 }
             }
         }
 
-        for rule in self.rules.iter() {
+        for (i, rule) in self.rules.iter().enumerate() {
             if rule.matches_user(user) {
                 let variation = rule.variation_index_for_user(user, self.key(), self.salt());
 
                 return IndexResult {
-                    value: variation,
-                    explanation: Explanation::Rule(rule.clone()),
+                    value: variation.map(|(index, _)| index),
+                    explanation: Explanation::Rule {
+                        index: i,
+                        in_experiment: variation.map_or(false, |(_, exp)| exp),
+                    },
                 };
             }
         }
 
+        let variation = self.fallthrough
+            .variation_index_for_user(user, self.key(), self.salt());
+
         IndexResult {
-            value: self.fallthrough
-                .variation_index_for_user(user, self.key(), self.salt()),
-            explanation: Explanation::VariationOrRollOut(self.fallthrough.clone()),
+            value: variation.map(|(index, _)| index),
+            explanation: Explanation::Fallthrough {
+                in_experiment: variation.map_or(false, |(_, exp)| exp),
+            },
         }
     }
 
@@ -347,6 +592,22 @@ impl FeatureFlag {
             .ok_or(FlagError::InvalidVariationIndex)
     }
 
+    pub fn prerequisite(&self, i: usize) -> Option<&Prerequisite> {
+        self.prerequisites.get(i)
+    }
+
+    pub fn prerequisites(&self) -> &[Prerequisite] {
+        &self.prerequisites
+    }
+
+    pub fn rule(&self, i: usize) -> Option<&Rule> {
+        self.rules.get(i)
+    }
+
+    pub fn target(&self, i: usize) -> Option<&Target> {
+        self.targets.get(i)
+    }
+
     pub fn key(&self) -> &str {
         self.key.as_str()
     }
@@ -367,6 +628,39 @@ impl FeatureFlag {
         self.on
     }
 
+    pub fn track_events(&self) -> bool {
+        self.track_events
+    }
+
+    pub fn track_events_fallthrough(&self) -> bool {
+        self.track_events_fallthrough
+    }
+
+    /// Whether an evaluation with the given explanation should be recorded as a
+    /// full feature event, either because the flag is tracked as a whole,
+    /// because the matched rule or fallthrough is, or because the user is in
+    /// an experiment
+    pub fn tracks(&self, explanation: &Explanation) -> bool {
+        self.track_events
+            || explanation.in_experiment()
+            || match *explanation {
+                Explanation::Rule { index, .. } => self
+                    .rules
+                    .get(index)
+                    .map_or(false, |rule| rule.track_events),
+                Explanation::Fallthrough { .. } => self.track_events_fallthrough,
+                _ => false,
+            }
+    }
+
+    pub fn client_side(&self) -> bool {
+        self.client_side
+    }
+
+    pub fn debug_events_until_date(&self) -> Option<i64> {
+        self.debug_events_until_date
+    }
+
     pub fn deleted(&self) -> bool {
         self.deleted
     }
@@ -382,11 +676,11 @@ impl FromRedisValue for FeatureFlag {
             RedisValue::Data(ref data) => {
                 let data = String::from_utf8(data.clone());
 
-                data.or_else(|_| Err((ErrorKind::TypeError, "Expected utf8 string").into()))
+                data.or_else(|_| Err((RedisErrorKind::TypeError, "Expected utf8 string").into()))
                     .and_then(|ser| {
                         serde_json::from_str(ser.as_str()).or_else(|_| {
                             let err = (
-                                ErrorKind::TypeError,
+                                RedisErrorKind::TypeError,
                                 "Unable to deserialize json to FeatureFlag",
                             );
                             Err(err.into())
@@ -396,7 +690,7 @@ impl FromRedisValue for FeatureFlag {
             ref x => {
                 println!("{:?}", x);
                 let err = (
-                    ErrorKind::TypeError,
+                    RedisErrorKind::TypeError,
                     "Recieved non-data type for deserializing",
                 );
                 Err(err.into())
@@ -406,24 +700,24 @@ impl FromRedisValue for FeatureFlag {
 }
 
 impl<'a> ToRedisArgs for &'a FeatureFlag {
-    fn to_redis_args(&self) -> Vec<Vec<u8>> {
+    fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
         let ser = serde_json::to_string(&self);
 
-        vec![
-            match ser {
-                Ok(json) => json.as_bytes().into(),
+        match ser {
+            Ok(json) => out.write_arg(json.as_bytes()),
 
-                // Because this trait can not normally fail, but json serialization
-                // can fail, the failure cause is encoded as a special value that
-                // is checked by the store
-                Err(_) => "fail".to_string().as_bytes().into(),
-            },
-        ]
+            // Because this trait can not normally fail, but json serialization
+            // can fail, the failure cause is encoded as a special value that
+            // is checked by the store
+            Err(_) => out.write_arg(b"fail"),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use feature_flag::*;
     use mem_store::*;
     use store::*;
@@ -473,37 +767,43 @@ mod tests {
         let wv1 = WeightedVariation {
             variation: 0,
             weight: 60000,
+            untracked: false,
         };
         let wv2 = WeightedVariation {
             variation: 1,
             weight: 40000,
+            untracked: false,
         };
         let rollout = Rollout {
             weighted_variations: vec![wv1, wv2],
             bucket_by: None,
+            kind: None,
+            seed: None,
         };
         let rule = Rule {
+            id: None,
             variation_or_rollout: VariationOrRollOut::Rollout(rollout),
             clauses: vec![],
+            track_events: false,
         };
 
         let user_key_a = "userKeyA";
         let user_a = UserBuilder::new(user_key_a).build();
         let v_1 = rule.variation_index_for_user(&user_a, "hashKey", "saltyA");
         assert!(v_1.is_some(), "Variation 1 should not be None");
-        assert_eq!(0, v_1.unwrap());
+        assert_eq!((0, false), v_1.unwrap());
 
         let user_key_b = "userKeyB";
         let user_b = UserBuilder::new(user_key_b).build();
         let v_2 = rule.variation_index_for_user(&user_b, "hashKey", "saltyA");
         assert!(v_2.is_some(), "Variation 2 should not be None");
-        assert_eq!(1, v_2.unwrap());
+        assert_eq!((1, false), v_2.unwrap());
 
         let user_key_c = "userKeyC";
         let user_c = UserBuilder::new(user_key_c).build();
         let v_3 = rule.variation_index_for_user(&user_c, "hashKey", "saltyA");
         assert!(v_3.is_some(), "Variation 3 should not be None");
-        assert_eq!(0, v_3.unwrap());
+        assert_eq!((0, false), v_3.unwrap());
     }
 
     #[test]
@@ -515,13 +815,11 @@ mod tests {
         store.upsert(f1.key(), &f1);
 
         let eval = f1.evaluate(&user, &store);
-        let explanation = Explanation::Prerequisite(Prerequisite {
-            key: "keyB".into(),
-            variation: 0,
-        });
+        let explanation = Explanation::Prerequisite(0);
 
         assert_eq!(eval.result.value, Err(FlagError::FailedToSatisfyPrereq));
         assert_eq!(eval.result.explanation, explanation);
+        assert_eq!(f1.prerequisite(0).map(|p| p.key.as_str()), Some("keyB"));
         assert_eq!(eval.events.len(), 0);
     }
 
@@ -549,4 +847,178 @@ mod tests {
         assert_eq!(f3_eval.result.value, Ok(VariationValue::Integer(0)));
         assert_eq!(f3_eval.events.len(), 0);
     }
+
+    #[test]
+    fn test_deserializes_wire_flags() {
+        let flags: HashMap<String, FeatureFlag> =
+            serde_json::from_str(include_str!("../fixtures/latest_flags.json")).unwrap();
+
+        let flag = flags.get("feature.with-rules").unwrap();
+        assert_eq!(flag.version(), 12);
+        assert!(flag.track_events());
+        assert!(flag.client_side());
+        assert_eq!(flag.debug_events_until_date(), Some(1500000000000));
+        assert_eq!(flag.prerequisite(0).map(|p| p.variation), Some(1));
+        assert_eq!(flag.target(0).and_then(|t| t.variation), Some(1));
+
+        let rule = flag.rule(0).unwrap();
+        assert_eq!(
+            rule.id.as_ref().map(|id| id.as_str()),
+            Some("3c1f5e2a-8b7d-4c6e-9f0a-1b2c3d4e5f60")
+        );
+        assert_eq!(rule.variation_or_rollout, VariationOrRollOut::Variation(0));
+
+        match flag.rule(1).unwrap().variation_or_rollout {
+            VariationOrRollOut::Rollout(ref rollout) => {
+                assert_eq!(rollout.weighted_variations.len(), 2);
+                assert_eq!(rollout.bucket_by, Some("email".into()));
+            }
+            ref other => panic!("Expected inline rollout, found {:?}", other),
+        }
+
+        // Unsupported operators are tolerated, but never match
+        let user = UserBuilder::new("user-c").build();
+        assert!(!flag.rule(2).unwrap().matches_user(&user));
+
+        let prereq = flags.get("feature.prereq").unwrap();
+        assert!(!prereq.on());
+        assert!(!prereq.deleted());
+        assert_eq!(prereq.fallthrough, VariationOrRollOut::Variation(1));
+        assert_eq!(
+            prereq.variation(1),
+            Ok(VariationValue::Json(
+                serde_json::from_str(r#"{"plan": "gold", "seats": 10}"#).unwrap()
+            ))
+        );
+        assert_eq!(
+            prereq.variation(2),
+            Ok(VariationValue::Json(serde_json::Value::Null))
+        );
+    }
+
+    #[test]
+    fn test_deserializes_minimal_flag() {
+        let flag: FeatureFlag =
+            serde_json::from_str(include_str!("../fixtures/minimal_flag.json")).unwrap();
+        let user = UserBuilder::new("userKey").build();
+
+        assert_eq!(flag.key(), "feature.minimal");
+        assert!(!flag.on());
+        assert!(!flag.deleted());
+        assert!(!flag.track_events());
+        assert_eq!(flag.off_variation(), None);
+        assert_eq!(flag.eval_index(&user).value, None);
+    }
+
+    #[test]
+    fn test_wire_flags_round_trip() {
+        let flags: HashMap<String, FeatureFlag> =
+            serde_json::from_str(include_str!("../fixtures/latest_flags.json")).unwrap();
+
+        for flag in flags.values() {
+            let json = serde_json::to_string(flag).unwrap();
+            let parsed: FeatureFlag = serde_json::from_str(json.as_str()).unwrap();
+            assert_eq!(&parsed, flag);
+        }
+    }
+
+    #[test]
+    fn test_tracks_by_rule_and_fallthrough() {
+        let flags: HashMap<String, FeatureFlag> =
+            serde_json::from_str(include_str!("../fixtures/latest_flags.json")).unwrap();
+        let mut flag = flags.get("feature.with-rules").unwrap().clone();
+
+        let rule = |index| Explanation::Rule {
+            index: index,
+            in_experiment: false,
+        };
+        let fallthrough = Explanation::Fallthrough {
+            in_experiment: false,
+        };
+
+        assert!(flag.tracks(&Explanation::Target(0)));
+
+        flag.track_events = false;
+        flag.rules[1].track_events = true;
+        assert!(!flag.tracks(&rule(0)));
+        assert!(flag.tracks(&rule(1)));
+        assert!(!flag.tracks(&fallthrough));
+        assert!(flag.tracks(&Explanation::Fallthrough {
+            in_experiment: true,
+        }));
+
+        flag.track_events_fallthrough = true;
+        assert!(flag.tracks(&fallthrough));
+        assert!(!flag.tracks(&Explanation::Target(0)));
+    }
+
+    #[test]
+    fn test_experiment_rollout() {
+        let flag: FeatureFlag = serde_json::from_str(
+            r#"{
+                "key": "experiment",
+                "version": 1,
+                "on": true,
+                "salt": "salt",
+                "fallthrough": {
+                    "rollout": {
+                        "kind": "experiment",
+                        "seed": 61,
+                        "bucketBy": "email",
+                        "variations": [
+                            {"variation": 0, "weight": 12000},
+                            {"variation": 1, "weight": 12000, "untracked": true},
+                            {"variation": 2, "weight": 76000}
+                        ]
+                    }
+                },
+                "variations": ["a", "b", "c"]
+            }"#,
+        ).unwrap();
+
+        let expectations = vec![
+            ("userKeyA", Some(0), true),
+            ("userKeyB", Some(1), false),
+            ("userKeyC", Some(2), true),
+        ];
+
+        for (key, variation, in_experiment) in expectations {
+            let user = UserBuilder::new(key).build();
+            let result = flag.eval_index(&user);
+
+            assert_eq!(result.value, variation, "{}", key);
+            assert_eq!(
+                result.explanation,
+                Explanation::Fallthrough {
+                    in_experiment: in_experiment,
+                },
+                "{}",
+                key
+            );
+            assert_eq!(flag.tracks(&result.explanation), in_experiment, "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_experiment_reason_serialization() {
+        let reason = Explanation::Rule {
+            index: 2,
+            in_experiment: true,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&reason).unwrap(),
+            r#"{"kind":"RULE_MATCH","ruleIndex":2,"inExperiment":true}"#
+        );
+    }
+
+    #[test]
+    fn test_error_reason_serialization() {
+        let reason = Explanation::Error(ErrorKind::ClientNotReady);
+
+        assert_eq!(
+            serde_json::to_string(&reason).unwrap(),
+            r#"{"kind":"ERROR","errorKind":"CLIENT_NOT_READY"}"#
+        );
+    }
 }
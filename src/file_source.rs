@@ -0,0 +1,322 @@
+use serde_json;
+use serde_json::Value as JsonValue;
+use serde_yaml;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use change::FlagChanges;
+use feature_flag::{FeatureFlag, VariationOrRollOut, VariationValue};
+use status::{Shutdown, StatusTracker};
+use store::Store;
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(PathBuf, io::Error),
+    Json(PathBuf, serde_json::Error),
+    Yaml(PathBuf, serde_yaml::Error),
+    DuplicateKey(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlagFile {
+    #[serde(default)]
+    flags: HashMap<String, FeatureFlag>,
+    /// Shorthand for flags that always serve one value
+    #[serde(default)]
+    flag_values: HashMap<String, VariationValue>,
+    // Segments are not evaluated yet
+    #[serde(default)]
+    segments: HashMap<String, JsonValue>,
+}
+
+/// Loads flags from local JSON or YAML files instead of the service, so that
+/// they have known values in development and tests. Files ending in `.yml`
+/// or `.yaml` are read as YAML, all others as JSON
+pub struct FileDataSource<S: Store + 'static> {
+    store: Arc<S>,
+    paths: Vec<PathBuf>,
+    watch: Option<Duration>,
+    status: StatusTracker,
+    changes: FlagChanges,
+    shutdown: Shutdown,
+    loads: usize,
+}
+
+impl<S: Store> FileDataSource<S> {
+    pub fn new(store: Arc<S>, paths: Vec<PathBuf>) -> FileDataSource<S> {
+        FileDataSource {
+            store: store,
+            paths: paths,
+            watch: None,
+            status: StatusTracker::new(),
+            changes: FlagChanges::new(),
+            shutdown: Shutdown::new(),
+            loads: 0,
+        }
+    }
+
+    /// Checks the files for modifications at this interval and reloads all
+    /// of them when one changes
+    pub fn watch(mut self, interval: Option<Duration>) -> Self {
+        self.watch = interval;
+        self
+    }
+
+    /// Tracker to report loading health to
+    pub fn status(mut self, status: StatusTracker) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Listeners to notify of flags changed by a reload
+    pub fn changes(mut self, changes: FlagChanges) -> Self {
+        self.changes = changes;
+        self
+    }
+
+    /// Signal that stops watching the files
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn run(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut modified = self.modified();
+            self.reload();
+
+            let interval = match self.watch {
+                Some(interval) => interval,
+                None => return,
+            };
+
+            while !self.shutdown.sleep(interval) {
+                let current = self.modified();
+                if current != modified {
+                    modified = current;
+                    self.reload();
+                }
+            }
+        })
+    }
+
+    fn reload(&mut self) {
+        self.loads += 1;
+
+        match self.load() {
+            Ok(flags) => match self.changes.init(&*self.store, flags) {
+                Ok(()) => self.status.valid(),
+                Err(err) => {
+                    warn!("Failed to store flags from files: {:?}", err);
+                    self.status.interrupted(err);
+                }
+            },
+            // The flags from the last successful load are kept
+            Err(err) => {
+                error!("Failed to load flags from files: {:?}", err);
+                self.status.interrupted(err);
+            }
+        }
+    }
+
+    fn load(&self) -> Result<HashMap<String, FeatureFlag>, FileError> {
+        let mut flags = HashMap::new();
+
+        for path in &self.paths {
+            let file = parse(path)?;
+
+            // Values have no version of their own. The stored flag keeps its
+            // version while the value is unchanged, otherwise the load count
+            // stands in for it so that listeners only hear of changed values
+            let values = file.flag_values.into_iter().map(|(key, value)| {
+                let version = match self.store.get(key.as_str()) {
+                    Some(ref flag) if **flag == value_flag(&key, value.clone(), flag.version()) => {
+                        flag.version()
+                    }
+                    _ => self.loads,
+                };
+                let flag = value_flag(key.as_str(), value, version);
+                (key, flag)
+            });
+
+            for (key, flag) in file.flags.into_iter().chain(values) {
+                if flags.contains_key(&key) {
+                    return Err(FileError::DuplicateKey(key));
+                }
+
+                flags.insert(key, flag);
+            }
+        }
+
+        Ok(flags)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+fn parse(path: &Path) -> Result<FlagFile, FileError> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| FileError::Io(path.to_path_buf(), err))?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yml") | Some("yaml") => serde_yaml::from_str(contents.as_str())
+            .map_err(|err| FileError::Yaml(path.to_path_buf(), err)),
+        _ => serde_json::from_str(contents.as_str())
+            .map_err(|err| FileError::Json(path.to_path_buf(), err)),
+    }
+}
+
+fn value_flag(key: &str, value: VariationValue, version: usize) -> FeatureFlag {
+    FeatureFlag::new(
+        key.into(),
+        version,
+        true,
+        vec![],
+        "".into(),
+        "".into(),
+        vec![],
+        vec![],
+        VariationOrRollOut::Variation(0),
+        Some(0),
+        vec![value],
+        false,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Write;
+    use std::process;
+    use std::sync::Mutex;
+
+    use file_source::*;
+    use mem_store::MemStore;
+
+    // Files are written to a directory of their own for every test run
+    fn write(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("dark_file_source_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        File::create(&path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_loads_json_and_yaml() {
+        let json = write(
+            "flags.json",
+            r#"{"flags": {"feature.minimal": {"key": "feature.minimal", "version": 4}}}"#,
+        );
+        let yaml = write("values.yaml", "flagValues:\n  feature.value: 42\n");
+
+        let store = Arc::new(MemStore::new());
+        let status = StatusTracker::new();
+        FileDataSource::new(store.clone(), vec![json, yaml])
+            .status(status.clone())
+            .run()
+            .join()
+            .unwrap();
+
+        assert!(status.initialized().is_set());
+        assert_eq!(store.get("feature.minimal").unwrap().version(), 4);
+        assert_eq!(
+            *store.get("feature.value").unwrap(),
+            value_flag("feature.value", VariationValue::Integer(42), 1)
+        );
+    }
+
+    #[test]
+    fn test_rejects_duplicate_keys() {
+        let first = write("first.json", r#"{"flagValues": {"f": 1}}"#);
+        let second = write("second.json", r#"{"flagValues": {"f": 2}}"#);
+        let source = FileDataSource::new(Arc::new(MemStore::new()), vec![first, second]);
+
+        match source.load() {
+            Err(FileError::DuplicateKey(ref key)) if key == "f" => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_reloads_modified_files() {
+        let path = write("watched.json", r#"{"flagValues": {"f": 1}}"#);
+        let store = Arc::new(MemStore::new());
+        let mut source = FileDataSource::new(store.clone(), vec![path.clone()]);
+
+        source.reload();
+        assert!(store.get("f").is_some());
+
+        write("watched.json", r#"{"flagValues": {"g": 1}}"#);
+        source.reload();
+
+        assert!(store.get("f").is_none());
+        assert!(store.get("g").is_some());
+    }
+
+    #[test]
+    fn test_watches_files_until_shut_down() {
+        let path = write("run.json", r#"{"flagValues": {"f": 1}}"#);
+        let store = Arc::new(MemStore::new());
+        let status = StatusTracker::new();
+        let shutdown = Shutdown::new();
+        let handle = FileDataSource::new(store.clone(), vec![path])
+            .watch(Some(Duration::from_millis(10)))
+            .status(status.clone())
+            .shutdown(shutdown.clone())
+            .run();
+
+        assert!(status.initialized().wait(Duration::from_secs(1)));
+        assert!(store.get("f").is_some());
+
+        write("run.json", r#"{"flagValues": {"g": 1}}"#);
+        let mut attempts = 0;
+        while store.get("g").is_none() && attempts < 100 {
+            thread::sleep(Duration::from_millis(10));
+            attempts += 1;
+        }
+        assert!(store.get("f").is_none());
+        assert!(store.get("g").is_some());
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_unchanged_values_keep_their_version() {
+        let path = write("versioned.json", r#"{"flagValues": {"f": 1, "g": 1}}"#);
+        let store = Arc::new(MemStore::new());
+        let changes = FlagChanges::new();
+        let changed = Arc::new(Mutex::new(vec![]));
+        let c = changed.clone();
+        changes.listen(move |key: &str| c.lock().unwrap().push(key.to_string()));
+
+        let mut source = FileDataSource::new(store.clone(), vec![path.clone()]).changes(changes);
+        source.reload();
+        changed.lock().unwrap().clear();
+
+        write("versioned.json", r#"{"flagValues": {"f": 1, "g": 2}}"#);
+        source.reload();
+
+        assert_eq!(store.get("f").unwrap().version(), 1);
+        assert_eq!(store.get("g").unwrap().version(), 2);
+        assert_eq!(*changed.lock().unwrap(), vec!["g".to_string()]);
+    }
+}
@@ -88,6 +88,16 @@ impl<T: Clone> HashCache<T> {
     pub fn remove<'a, S: Into<&'a str>>(&self, key: S) -> Option<T> {
         self.writer().remove(key.into()).map(|(v, _)| v)
     }
+
+    /// Swaps the whole contents of the cache for the given entries at once
+    pub fn replace(&self, entries: HashMap<String, T>) {
+        let now = Instant::now();
+        *self.writer() = entries.into_iter().map(|(k, v)| (k, (v, now))).collect();
+    }
+
+    pub fn clear(&self) {
+        self.writer().clear();
+    }
 }
 
 #[cfg(test)]
@@ -102,4 +112,17 @@ mod tests {
             .insert("3".into(), (vec![1, 2, 3], Instant::now()));
         assert_eq!(Some(vec![1, 2, 3]), cache.get("3"));
     }
+
+    #[test]
+    fn test_replace() {
+        let cache = HashCache::new(Duration::new(0, 0));
+        cache.insert("1", 1);
+
+        let mut entries = HashMap::new();
+        entries.insert("2".to_string(), 2);
+        cache.replace(entries);
+
+        assert_eq!(None, cache.get("1"));
+        assert_eq!(Some(2), cache.get("2"));
+    }
 }
@@ -1,43 +1,71 @@
 #![allow(dead_code, unused_must_use, unused_imports, unused_variables)]
 
 extern crate chrono;
-extern crate eventsource;
 #[macro_use]
 extern crate log;
+extern crate rand;
 extern crate redis;
 extern crate regex;
 extern crate reqwest;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+extern crate semver;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate serde_yaml;
 extern crate sha1;
 
+// Declared first so that its macro is available to the other modules
+#[macro_use]
+pub mod conformance;
+
+mod caching_store;
+mod change;
 mod clause;
 mod client;
 mod config;
 mod events;
 mod feature_flag;
+mod file_source;
 mod hash_cache;
+mod http;
 mod mem_store;
 mod poll;
+mod pool;
+mod read_only;
 mod redis_store;
 mod request;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+mod sse;
+mod status;
 mod store;
 mod stream;
+#[cfg(test)]
+mod test_server;
 mod user;
 
 const VERSION: &'static str = "0.1.0";
 
+pub use caching_store::{CacheInvalidator, CachingStore};
+pub use change::FlagChanges;
 pub use client::Client;
 pub use config::{Config, ConfigBuilder};
-pub use feature_flag::{FeatureFlag, VariationOrRollOut};
+pub use feature_flag::{ErrorKind, Explanation, FeatureFlag, VariationOrRollOut};
+pub use file_source::{FileDataSource, FileError};
+pub use http::{HttpConfig, HttpError};
 pub use mem_store::MemStore;
 pub use poll::Polling;
-pub use redis_store::RedisStore;
+pub use read_only::ReadOnlyStore;
+pub use redis_store::{RedisStore, RedisStoreBuilder};
 pub use request::Requestor;
-pub use store::{Store, StoreError, StoreResult};
-pub use stream::Streaming;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteStore;
+pub use status::{DataSourceStatus, Initialized, Shutdown, StatusTracker};
+pub use store::{Store, StoreError, StoreResult, StoreStatus};
+pub use stream::{Backoff, Streaming};
 pub use user::{User, UserBuilder};
 
 #[cfg(test)]
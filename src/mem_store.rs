@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use hash_cache::HashCache;
@@ -6,105 +8,115 @@ use feature_flag::FeatureFlag;
 use store::{Store, StoreError, StoreResult};
 
 pub struct MemStore {
-    data: HashCache<FeatureFlag>,
+    data: HashCache<Arc<FeatureFlag>>,
+    initialized: AtomicBool,
 }
 
 impl MemStore {
     pub fn new() -> MemStore {
         MemStore {
             data: HashCache::new(Duration::new(0, 0)),
+            initialized: AtomicBool::new(false),
         }
     }
 }
 
 impl From<HashMap<String, (FeatureFlag, Instant)>> for MemStore {
     fn from(map: HashMap<String, (FeatureFlag, Instant)>) -> MemStore {
-        MemStore { data: map.into() }
+        let data = map.into_iter()
+            .map(|(key, (flag, created))| (key, (Arc::new(flag), created)))
+            .collect::<HashMap<String, (Arc<FeatureFlag>, Instant)>>();
+
+        MemStore {
+            data: data.into(),
+            initialized: AtomicBool::new(false),
+        }
     }
 }
 
 impl Store for MemStore {
-    fn get(&self, key: &str) -> Option<FeatureFlag> {
+    fn get(&self, key: &str) -> Option<Arc<FeatureFlag>> {
         self.data
             .get(key)
             .and_then(|f| if !f.deleted() { Some(f) } else { None })
     }
 
     fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
-        let mut map = self.data.get_all();
-        map.retain(|_, flag| !flag.deleted());
-
-        Ok(map)
+        Ok(self.data
+            .get_all()
+            .into_iter()
+            .filter(|&(_, ref flag)| !flag.deleted())
+            .map(|(key, flag)| (key, (*flag).clone()))
+            .collect())
     }
 
     fn delete(&self, key: &str, version: usize) -> StoreResult<()> {
-        if let Some(flag) = self.get(key) {
-            if flag.version() < version {
-                let mut replacement = flag.clone();
-                replacement.delete();
-                replacement.update_version(version);
-                self.data.insert(key, replacement);
-                Ok(())
-            } else {
-                Err(StoreError::NewerVersionFound)
+        // Holds the write lock between the check and the write, so concurrent
+        // writers can not replace a newer version
+        let mut data = self.data.writer();
+
+        let replacement = match data.get(key) {
+            Some(&(ref flag, _)) if !flag.deleted() => {
+                if flag.version() < version {
+                    let mut replacement = (**flag).clone();
+                    replacement.delete();
+                    replacement.update_version(version);
+                    Ok(replacement)
+                } else {
+                    Err(StoreError::NewerVersionFound)
+                }
             }
-        } else {
-            Err(StoreError::NotFound)
-        }
+            _ => Err(StoreError::NotFound),
+        }?;
+
+        data.insert(key.into(), (Arc::new(replacement), Instant::now()));
+        Ok(())
     }
 
     fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
-        let replacement = if let Some(e_flag) = self.get(key) {
-            if e_flag.version() < flag.version() {
-                Ok(flag.clone())
-            } else {
+        let mut data = self.data.writer();
+
+        // Deleted flags are compared too, so a stale update can not revive them
+        if let Some(&(ref e_flag, _)) = data.get(key) {
+            if e_flag.version() >= flag.version() {
                 warn!(
                     "Can not overwrite flag with key {:?} in store with older version",
                     key
                 );
-                Err(StoreError::NewerVersionFound)
+                return Err(StoreError::NewerVersionFound);
             }
-        } else {
-            Ok(flag.clone())
-        }?;
+        }
 
-        self.data.insert(key, replacement);
+        data.insert(key.into(), (Arc::new(flag.clone()), Instant::now()));
         Ok(())
     }
 
     fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
-        for (key, flag) in flags {
-            self.upsert(key.as_str(), &flag);
-        }
+        self.data.replace(
+            flags
+                .into_iter()
+                .map(|(key, flag)| (key, Arc::new(flag)))
+                .collect(),
+        );
+        self.initialized.store(true, Ordering::SeqCst);
 
         Ok(())
     }
+
+    fn initialized(&self) -> bool {
+        self.initialized.load(Ordering::SeqCst)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::Barrier;
+    use std::thread;
 
-    use feature_flag::*;
+    use conformance::flag;
     use mem_store::*;
 
-    fn flag<S: Into<String>>(key: S, version: usize, deleted: bool) -> FeatureFlag {
-        FeatureFlag::new(
-            key.into(),
-            version,
-            true,
-            vec![],
-            "".into(),
-            "".into(),
-            vec![],
-            vec![],
-            VariationOrRollOut::Variation(0),
-            None,
-            vec![VariationValue::Integer(0), VariationValue::Integer(1)],
-            deleted,
-        )
-    }
-
     fn dataset() -> MemStore {
         let mut map = HashMap::new();
         let flags = vec![flag("f1", 5, false), flag("f2", 5, true)];
@@ -116,31 +128,52 @@ mod tests {
         map.into()
     }
 
-    #[test]
-    fn test_does_not_return_deleted() {
-        assert!(dataset().get("f2").is_none())
-    }
+    store_conformance_tests!(MemStore::new);
 
     #[test]
-    fn test_all_returns_only_not_deleted() {
-        let all = dataset().get_all().unwrap();
-        assert!(all.get("f1".into()).is_some());
-        assert!(all.get("f2".into()).is_none());
-    }
+    fn test_upsert_does_not_revive_newer_deleted() {
+        let store = dataset();
 
-    #[test]
-    fn test_delete_does_not_delete_newer_version() {
         assert_eq!(
-            dataset().delete("f1", 3),
+            store.upsert("f2", &flag("f2", 3, false)),
             Err(StoreError::NewerVersionFound)
-        )
+        );
+        assert!(store.get("f2").is_none());
     }
 
     #[test]
-    fn test_upsert_does_not_replace_newer_version() {
-        assert_eq!(
-            dataset().upsert("f1", &flag("f1", 3, false)),
-            Err(StoreError::NewerVersionFound)
-        )
+    fn test_racing_deletes_and_upserts_keep_highest_version() {
+        for _ in 0..1000 {
+            let store = Arc::new(dataset());
+            let start = Arc::new(Barrier::new(8));
+
+            let handles = (6..14)
+                .map(|version| {
+                    let store = store.clone();
+                    let start = start.clone();
+                    thread::spawn(move || {
+                        start.wait();
+                        let res = if version % 2 == 0 {
+                            store.delete("f1", version)
+                        } else {
+                            store.upsert("f1", &flag("f1", version, false))
+                        };
+                        (version, res)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut highest = 5;
+            for handle in handles {
+                match handle.join().unwrap() {
+                    (version, Ok(())) => highest = highest.max(version),
+                    (_, Err(StoreError::NewerVersionFound)) => (),
+                    (_, Err(StoreError::NotFound)) => (),
+                    (_, Err(err)) => panic!("unexpected error {:?}", err),
+                }
+            }
+
+            assert_eq!(store.data.get("f1").unwrap().version(), highest);
+        }
     }
 }
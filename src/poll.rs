@@ -1,34 +1,152 @@
+use rand;
+
 use std::thread;
 use std::time::Duration;
 use std::sync::Arc;
 
+use change::FlagChanges;
 use request::Requestor;
+use status::StatusTracker;
 use store::Store;
 
+/// Polling more often than this would put undue load on the service
+pub const MIN_POLL_INTERVAL: i64 = 30;
+
 pub struct Polling<S: Store + 'static> {
     store: Arc<S>,
     req: Arc<Requestor>,
     interval: i64,
+    status: StatusTracker,
+    changes: FlagChanges,
 }
 
 impl<S: Store> Polling<S> {
     pub fn new(store: Arc<S>, req: Arc<Requestor>, interval: i64) -> Polling<S> {
+        if interval < MIN_POLL_INTERVAL {
+            warn!(
+                "Poll interval of {}s is below the minimum, using {}s",
+                interval, MIN_POLL_INTERVAL
+            );
+        }
+
         Polling {
             store: store,
             req: req,
-            interval: interval,
+            interval: interval.max(MIN_POLL_INTERVAL),
+            status: StatusTracker::new(),
+            changes: FlagChanges::new(),
         }
     }
 
+    /// Tracker to report polling health to
+    pub fn status(mut self, status: StatusTracker) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Listeners to notify of flags changed between polls
+    pub fn changes(mut self, changes: FlagChanges) -> Self {
+        self.changes = changes;
+        self
+    }
+
     pub fn run(self) -> thread::JoinHandle<()> {
         thread::spawn(move || loop {
-            let res = self.req.get_all();
+            self.poll();
+            thread::sleep(jittered(Duration::new(self.interval as u64, 0)));
+        })
+    }
+
+    fn poll(&self) {
+        let res = self.req.get_all_if_modified();
+
+        // Unchanged flags are already in the store. The ETag is only kept once
+        // the flags are stored, so that a failed write is retried
+        let stored = res.map(|modified| match modified {
+            Some((flags, etag)) => self.changes
+                .init(&*self.store, flags)
+                .map(|_| self.req.commit_etag(etag)),
+            None => Ok(()),
+        });
 
-            if let Ok(flags) = res {
-                self.store.init(flags);
+        match stored {
+            Ok(Ok(())) => self.status.valid(),
+            Ok(Err(err)) => {
+                warn!("Failed to store polled flags: {:?}", err);
+                self.status.interrupted(err);
             }
+            Err(err) => {
+                warn!("Failed to poll flags: {:?}", err);
+                self.status.interrupted(err);
+            }
+        }
+    }
+}
 
-            thread::sleep(Duration::new(self.interval as u64, 0));
-        })
+// Adds up to a tenth of the interval at random so that many clients started
+// together do not poll in lockstep
+fn jittered(interval: Duration) -> Duration {
+    let millis = interval.as_secs() * 1000 + u64::from(interval.subsec_nanos() / 1_000_000);
+    let jitter = (millis as f64 * rand::random::<f64>() / 10.0) as u64;
+
+    interval + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use mem_store::MemStore;
+    use poll::*;
+    use read_only::ReadOnlyStore;
+    use test_server::serve;
+
+    const FLAGS: &'static str = "HTTP/1.1 200 OK\r\n\
+                                 ETag: \"v1\"\r\n\
+                                 Content-Length: 2\r\n\
+                                 Connection: close\r\n\r\n{}";
+
+    #[test]
+    fn test_enforces_minimum_interval() {
+        let req = Arc::new(Requestor::new("http://127.0.0.1:1", "key").unwrap());
+        let polling = Polling::new(Arc::new(MemStore::new()), req, 1);
+
+        assert_eq!(polling.interval, MIN_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn test_jitter_is_bounded() {
+        let interval = Duration::from_secs(30);
+
+        for _ in 0..100 {
+            let delay = jittered(interval);
+            assert!(delay >= interval && delay <= Duration::from_secs(33));
+        }
+    }
+
+    #[test]
+    fn test_keeps_etag_once_stored() {
+        let (addr, requests) = serve(vec![FLAGS, FLAGS]);
+        let req = Arc::new(Requestor::new(addr, "key").unwrap());
+        let polling = Polling::new(Arc::new(MemStore::new()), req, 30);
+
+        polling.poll();
+        polling.poll();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+    }
+
+    #[test]
+    fn test_drops_etag_when_not_stored() {
+        let (addr, requests) = serve(vec![FLAGS, FLAGS]);
+        let req = Arc::new(Requestor::new(addr, "key").unwrap());
+        let store = ReadOnlyStore::new(MemStore::new());
+        let polling = Polling::new(Arc::new(store), req, 30);
+
+        polling.poll();
+        polling.poll();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[1].contains("if-none-match"));
     }
 }
@@ -0,0 +1,280 @@
+use redis::{cmd, Client, Connection, ErrorKind, RedisResult};
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use store::{StoreError, StoreResult};
+
+// Connections idle for longer than this are pinged before being handed out,
+// as the server may have closed them in the meantime
+const VALIDATE_AFTER_IDLE: Duration = Duration::from_secs(1);
+
+struct PoolState {
+    // Returned connections with the time they were returned
+    idle: Vec<(Connection, Instant)>,
+    open: usize,
+}
+
+/// A bounded set of Redis connections shared between threads. Connections are
+/// opened on demand up to `max_size`, after which checkouts wait for one to
+/// be returned. Connections found broken are closed along with every idle one,
+/// which most likely broke with it
+pub struct Pool {
+    client: Client,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+    max_size: usize,
+    checkout_timeout: Duration,
+    operation_timeout: Option<Duration>,
+}
+
+impl Pool {
+    pub fn new(client: Client, max_size: usize, checkout_timeout: Duration) -> Pool {
+        Pool {
+            client: client,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                open: 0,
+            }),
+            returned: Condvar::new(),
+            max_size: max_size,
+            checkout_timeout: checkout_timeout,
+            operation_timeout: None,
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn set_checkout_timeout(&mut self, checkout_timeout: Duration) {
+        self.checkout_timeout = checkout_timeout;
+    }
+
+    /// Applied to reads and writes on connections opened from now on
+    pub fn set_operation_timeout(&mut self, operation_timeout: Option<Duration>) {
+        self.operation_timeout = operation_timeout;
+    }
+
+    pub fn get(&self) -> StoreResult<PooledConnection> {
+        let deadline = Instant::now() + self.checkout_timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some((mut conn, returned)) = state.idle.pop() {
+                if returned.elapsed() < VALIDATE_AFTER_IDLE {
+                    return Ok(PooledConnection::new(self, conn));
+                }
+
+                drop(state);
+                let res: RedisResult<()> = cmd("PING").query(&mut conn);
+                if res.is_ok() {
+                    return Ok(PooledConnection::new(self, conn));
+                }
+
+                debug!("Closing broken Redis connection: {:?}", res);
+                self.release(None);
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+
+                // Connect without holding the lock so other checkouts proceed
+                return match self.connect() {
+                    Ok(conn) => Ok(PooledConnection::new(self, conn)),
+                    Err(err) => {
+                        self.release(None);
+                        Err(StoreError::RedisFailure(err))
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(StoreError::PoolTimeout);
+            }
+
+            state = self.returned.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn connect(&self) -> RedisResult<Connection> {
+        let conn = self.client.get_connection()?;
+        conn.set_read_timeout(self.operation_timeout)?;
+        conn.set_write_timeout(self.operation_timeout)?;
+
+        Ok(conn)
+    }
+
+    // Returns a connection to the pool, or frees its slot if it is broken
+    fn release(&self, conn: Option<Connection>) {
+        let mut state = self.state.lock().unwrap();
+
+        match conn {
+            Some(conn) => {
+                state.idle.push((conn, Instant::now()));
+                self.returned.notify_one();
+            }
+            None => {
+                // A broken connection usually means the server restarted or
+                // dropped its clients, so the idle ones would fail next
+                let closed = 1 + state.idle.len();
+                state.idle.clear();
+                state.open -= closed;
+                self.returned.notify_all();
+            }
+        }
+    }
+}
+
+/// A connection checked out of a `Pool`, returned to it when dropped
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+    broken: bool,
+}
+
+impl<'a> PooledConnection<'a> {
+    fn new(pool: &'a Pool, conn: Connection) -> PooledConnection<'a> {
+        PooledConnection {
+            pool: pool,
+            conn: Some(conn),
+            broken: false,
+        }
+    }
+
+    /// Passes the result through, marking the connection as broken on I/O
+    /// errors so that it is replaced rather than reused
+    pub fn check<T>(&mut self, res: RedisResult<T>) -> RedisResult<T> {
+        if let Err(ref err) = res {
+            if err.kind() == ErrorKind::IoError {
+                self.broken = true;
+            }
+        }
+
+        res
+    }
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        let conn = self.conn.take();
+        self.pool.release(if self.broken { None } else { conn });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::RedisError;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use pool::*;
+
+    fn pool(max_size: usize) -> Pool {
+        let client = Client::open("redis://0.0.0.0:6379").unwrap();
+        Pool::new(client, max_size, Duration::from_millis(50))
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_reuses_connections() {
+        let pool = pool(1);
+
+        for _ in 0..5 {
+            let mut conn = pool.get().unwrap();
+            let res: RedisResult<String> = cmd("PING").query(&mut *conn);
+            assert_eq!(res.unwrap(), "PONG");
+        }
+
+        assert_eq!(pool.state.lock().unwrap().open, 1);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_checkout_times_out_when_exhausted() {
+        let pool = pool(1);
+        let conn = pool.get().unwrap();
+
+        match pool.get() {
+            Err(StoreError::PoolTimeout) => (),
+            _ => panic!("expected the checkout to time out"),
+        }
+
+        drop(conn);
+        assert!(pool.get().is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_replaces_broken_connections() {
+        let pool = pool(2);
+
+        {
+            let idle = pool.get().unwrap();
+            let mut conn = pool.get().unwrap();
+            drop(idle);
+
+            let err: RedisResult<()> = Err(RedisError::from((ErrorKind::IoError, "reset")));
+            assert!(conn.check(err).is_err());
+        }
+
+        let state = pool.state.lock().unwrap();
+        assert_eq!(state.open, 0);
+        assert!(state.idle.is_empty());
+    }
+
+    #[test]
+    fn test_validates_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Closes the first connection, then answers every command on the next
+        thread::spawn(move || {
+            drop(listener.accept().unwrap());
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 || stream.write_all(b"+PONG\r\n").is_err() {
+                    break;
+                }
+            }
+        });
+
+        let client = Client::open(format!("redis://{}", addr).as_str()).unwrap();
+        let pool = Pool::new(client, 1, Duration::from_millis(50));
+        drop(pool.get().unwrap());
+
+        let returned = Instant::now() - VALIDATE_AFTER_IDLE;
+        pool.state.lock().unwrap().idle[0].1 = returned;
+
+        let mut conn = pool.get().unwrap();
+        let res: RedisResult<String> = cmd("PING").query(&mut *conn);
+        assert_eq!(res.unwrap(), "PONG");
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use feature_flag::FeatureFlag;
+use store::{Store, StoreError, StoreResult, StoreStatus};
+
+/// Wraps a store populated by another process, such as a relay with
+/// `use_ldd`, rejecting every write so that it is never modified from here
+pub struct ReadOnlyStore<S: Store> {
+    store: S,
+}
+
+impl<S: Store> ReadOnlyStore<S> {
+    pub fn new(store: S) -> ReadOnlyStore<S> {
+        ReadOnlyStore { store: store }
+    }
+}
+
+impl<S: Store> Store for ReadOnlyStore<S> {
+    fn get(&self, key: &str) -> Option<Arc<FeatureFlag>> {
+        self.store.get(key)
+    }
+
+    fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
+        self.store.get_all()
+    }
+
+    fn delete(&self, key: &str, _version: usize) -> StoreResult<()> {
+        warn!("Refusing to delete flag {:?} from a read only store", key);
+        Err(StoreError::ReadOnly)
+    }
+
+    fn upsert(&self, key: &str, _flag: &FeatureFlag) -> StoreResult<()> {
+        warn!("Refusing to update flag {:?} in a read only store", key);
+        Err(StoreError::ReadOnly)
+    }
+
+    fn init(&self, _flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
+        warn!("Refusing to initialize a read only store");
+        Err(StoreError::ReadOnly)
+    }
+
+    fn initialized(&self) -> bool {
+        self.store.initialized()
+    }
+
+    fn status(&self) -> StoreStatus {
+        self.store.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use feature_flag::*;
+    use mem_store::MemStore;
+    use read_only::*;
+
+    fn flag(key: &str) -> FeatureFlag {
+        FeatureFlag::new(
+            key.into(),
+            1,
+            true,
+            vec![],
+            "".into(),
+            "".into(),
+            vec![],
+            vec![],
+            VariationOrRollOut::Variation(0),
+            None,
+            vec![VariationValue::Integer(0)],
+            false,
+        )
+    }
+
+    #[test]
+    fn test_rejects_writes() {
+        let store = ReadOnlyStore::new(MemStore::new());
+
+        assert_eq!(store.upsert("f1", &flag("f1")), Err(StoreError::ReadOnly));
+        assert_eq!(store.delete("f1", 2), Err(StoreError::ReadOnly));
+        assert_eq!(store.init(HashMap::new()), Err(StoreError::ReadOnly));
+        assert!(store.get("f1").is_none());
+    }
+
+    #[test]
+    fn test_reads_from_wrapped_store() {
+        let inner = MemStore::new();
+        let mut flags = HashMap::new();
+        flags.insert("f1".to_string(), flag("f1"));
+        inner.init(flags).unwrap();
+
+        let store = ReadOnlyStore::new(inner);
+
+        assert!(store.initialized());
+        assert!(store.get("f1").is_some());
+        assert_eq!(store.get_all().unwrap().len(), 1);
+    }
+}
@@ -1,30 +1,58 @@
-use redis::{cmd, Client, Commands, Connection, FromRedisValue, RedisResult, ToRedisArgs};
+use redis::{
+    cmd, pipe, Client, Commands, ConnectionAddr, ConnectionInfo, IntoConnectionInfo,
+    RedisConnectionInfo, RedisResult, ToRedisArgs,
+};
 
 use std::collections::HashMap;
-use std::time::Duration;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use caching_store::{CacheInvalidator, CachingStore};
 use feature_flag::FeatureFlag;
 use hash_cache::HashCache;
-use store::{Store, StoreError, StoreResult};
+use pool::{Pool, PooledConnection};
+use store::{Store, StoreError, StoreResult, StoreStatus};
 
 const FAIL: &'static [u8; 4] = &[102, 97, 105, 108];
-const ALL_CACHE: &'static str = "$all_flags$";
+// Published instead of a flag key when the whole data set is replaced
+const ALL_CHANGED: &'static str = "*";
+// How long the subscriber blocks waiting for a message before checking
+// whether the store was dropped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+// How long a missing initialization marker is trusted before asking Redis
+// again, so that uninitialized readers do not query it on every evaluation
+const INITED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Persists flags in a Redis hash. Reads are not cached, the `open`
+/// functions and `RedisStoreBuilder` wrap the store in a `CachingStore`
 pub struct RedisStore {
     key: String,
-    client: Client,
-    cache: HashCache<FeatureFlag>,
-    all_cache: HashCache<HashMap<String, FeatureFlag>>,
-    timeout: Duration,
+    inited_key: String,
+    changes_channel: String,
+    pool: Pool,
+    // Last flags read from or written to Redis, served while it is unavailable
+    stale: HashCache<Arc<FeatureFlag>>,
+    initialized: AtomicBool,
+    // Whether the full data set was written here, which is what makes the
+    // stale flags complete enough to restore
+    written: AtomicBool,
+    // When the marker was last found missing
+    inited_checked: Mutex<Option<Instant>>,
+    status: Mutex<StoreStatus>,
+    subscriber: Mutex<Option<(Arc<AtomicBool>, thread::JoinHandle<()>)>>,
 }
 
 impl RedisStore {
+    /// Opens a store whose flags are cached for `timeout`, forever by default
     pub fn open(
         host: String,
         port: u32,
         prefix: Option<String>,
         timeout: Option<Duration>,
-    ) -> StoreResult<RedisStore> {
+    ) -> StoreResult<CachingStore<RedisStore>> {
         RedisStore::open_with_url(format!("redis://{}:{}", host, port), prefix, timeout)
     }
 
@@ -32,230 +60,1008 @@ impl RedisStore {
         url: String,
         prefix: Option<String>,
         timeout: Option<Duration>,
-    ) -> StoreResult<RedisStore> {
-        let client = Client::open(url.as_str()).map_err(|_| StoreError::InvalidRedisConfig)?;
+    ) -> StoreResult<CachingStore<RedisStore>> {
+        let mut builder = RedisStoreBuilder::new().url(url.as_str());
+        if let Some(prefix) = prefix {
+            builder = builder.prefix(prefix.as_str());
+        }
+        if let Some(timeout) = timeout {
+            builder = builder.cache_ttl(timeout);
+        }
 
-        Ok(RedisStore::open_with_client(client, prefix, timeout))
+        builder.build()
     }
 
     pub fn open_with_client(
         client: Client,
         prefix: Option<String>,
         timeout: Option<Duration>,
-    ) -> RedisStore {
+    ) -> CachingStore<RedisStore> {
         let dur = timeout.unwrap_or(Duration::new(0, 0));
+        CachingStore::new(RedisStore::new(client, prefix), dur)
+    }
+
+    /// Opens a store without a cache, reading every flag from Redis
+    pub fn new(client: Client, prefix: Option<String>) -> RedisStore {
+        let prefix = prefix.unwrap_or("launchdarkly".into());
 
         RedisStore {
-            key: RedisStore::features_key(prefix),
-            client: client,
-            cache: HashCache::new(dur),
-            all_cache: HashCache::new(dur),
-            timeout: dur,
+            key: format!("{}:features", prefix),
+            inited_key: format!("{}:$inited", prefix),
+            changes_channel: format!("{}:changes", prefix),
+            pool: Pool::new(client, 16, Duration::from_secs(5)),
+            stale: HashCache::new(Duration::new(0, 0)),
+            initialized: AtomicBool::new(false),
+            written: AtomicBool::new(false),
+            inited_checked: Mutex::new(None),
+            status: Mutex::new(StoreStatus::Available),
+            subscriber: Mutex::new(None),
         }
     }
 
-    fn features_key(prefix: Option<String>) -> String {
-        prefix.unwrap_or("launchdarkly".into()) + ":features"
+    /// How old the last known flags may be to still be served while Redis is
+    /// unavailable, unbounded by default
+    pub fn max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.stale = HashCache::new(max_staleness);
+        self
+    }
+
+    /// Timeout for reading and writing each command, none by default
+    pub fn operation_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pool.set_operation_timeout(timeout);
+        self
+    }
+
+    /// Maximum number of connections kept open to Redis, 16 by default
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool.set_max_size(size);
+        self
     }
 
-    fn conn(&self) -> StoreResult<Connection> {
+    /// How long to wait for a connection when all of them are in use, 5
+    /// seconds by default
+    pub fn checkout_timeout(mut self, timeout: Duration) -> Self {
+        self.pool.set_checkout_timeout(timeout);
+        self
+    }
+
+    /// Listens for changes written by other processes and drops the affected
+    /// entries from `cache` as soon as they are notified, rather than when
+    /// they expire. Keyspace notifications for the hash are handled as well
+    /// when the server has them enabled with `notify-keyspace-events Kh`
+    pub fn subscribe_changes(&self, cache: CacheInvalidator) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let subscriber = Subscriber {
+            client: self.pool.client().clone(),
+            channel: self.changes_channel.clone(),
+            pattern: format!("__keyspace@*__:{}", self.key),
+            cache: cache,
+            stop: stop.clone(),
+        };
+
+        let handle = thread::spawn(move || subscriber.run());
+
+        // A previous subscriber is replaced
+        let previous = self.subscriber.lock().unwrap().replace((stop, handle));
+        stop_subscriber(previous);
+    }
+
+    fn conn(&self) -> StoreResult<PooledConnection> {
         // Get a single connection to group requests on
-        self.client
-            .get_connection()
-            .map_err(StoreError::RedisFailure)
+        self.pool.get()
     }
 
-    fn get_raw(&self, key: &str, conn: &Connection) -> Option<FeatureFlag> {
-        conn.hget(self.key.to_string(), key.to_string()).ok()
+    fn get_raw(&self, key: &str, conn: &mut PooledConnection) -> RedisResult<Option<FeatureFlag>> {
+        let res = conn.hget(self.key.to_string(), key.to_string());
+        conn.check(res)
     }
 
-    fn put(&self, key: &str, flag: &FeatureFlag, conn: &Connection) -> StoreResult<()> {
-        // Manually serialize to redis storable value to allow for failure handling
-        let flag_ser = flag.to_redis_args();
+    // Tracks whether Redis is reachable from the outcome of an operation
+    fn record<T>(&self, res: StoreResult<T>) -> StoreResult<T> {
+        match res {
+            Ok(_) => self.available(),
+            Err(StoreError::RedisFailure(ref err)) => self.unavailable(err),
+            Err(StoreError::PoolTimeout) => self.unavailable(&StoreError::PoolTimeout),
+            Err(_) => (),
+        }
 
-        if flag_ser[0].as_slice() != FAIL {
-            let res: RedisResult<u8> =
-                conn.hset(self.key.to_string(), flag.key().to_string(), flag_ser);
+        res
+    }
 
-            self.all_cache.remove(ALL_CACHE);
-            self.cache.insert(key, flag.clone());
+    fn unavailable<E: Debug>(&self, err: &E) {
+        let mut status = self.status.lock().unwrap();
 
-            res.map(|_| ()).map_err(StoreError::RedisFailure)
-        } else {
-            Err(StoreError::FailedToSerializeFlag)
+        let since = match *status {
+            StoreStatus::Unavailable { since, .. } => since,
+            StoreStatus::Available => {
+                warn!("Redis is unavailable, serving last known flags: {:?}", err);
+                SystemTime::now()
+            }
+        };
+
+        *status = StoreStatus::Unavailable {
+            last_error: format!("{:?}", err),
+            since: since,
+        };
+    }
+
+    fn available(&self) {
+        let recovered = {
+            let mut status = self.status.lock().unwrap();
+            let recovered = *status != StoreStatus::Available;
+            *status = StoreStatus::Available;
+            recovered
+        };
+
+        if recovered {
+            info!("Redis is available again");
+            self.restore();
         }
     }
 
-    fn start<T: FromRedisValue>(&self, key: &str, conn: &Connection) -> StoreResult<()> {
-        let res: RedisResult<T> = cmd("WATCH").arg(key).query(conn);
-        res.map(|_| ()).map_err(StoreError::RedisFailure)
+    // Writes the last known flags back if Redis lost them while it was down.
+    // Only stores that wrote the data set restore it, readers such as those
+    // of a relay's store must leave that to the relay
+    fn restore(&self) {
+        if !self.written.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let exists = self.conn().and_then(|mut conn| {
+            let res = conn.exists(self.inited_key.as_str());
+            conn.check(res).map_err(StoreError::RedisFailure)
+        });
+
+        if let Ok(false) = exists {
+            // Setting the marker over a partial set would hide the missing
+            // flags until the next full write
+            if self.stale.reader().len() != self.stale.get_all().len() {
+                error!("Redis lost its data while unavailable, and the last known flags are too old to restore");
+                return;
+            }
+
+            warn!("Redis lost its data while unavailable, restoring the last known flags");
+
+            let flags = self
+                .stale
+                .get_all()
+                .into_iter()
+                .map(|(key, flag)| (key, (*flag).clone()))
+                .collect();
+
+            if let Err(err) = self.write_all(flags) {
+                error!("Failed to restore flags to Redis: {:?}", err);
+            }
+        }
     }
 
-    fn cleanup<T: FromRedisValue>(&self, conn: &Connection) -> StoreResult<()> {
-        let res: RedisResult<T> = cmd("UNWATCH").query(conn);
-        res.map(|_| ()).map_err(StoreError::RedisFailure)
+    fn write_all(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
+        let mut conn = self.conn()?;
+
+        // Replaces the hash in one transaction so readers never see a partial set
+        let mut pipeline = pipe();
+        pipeline.atomic().cmd("DEL").arg(self.key.as_str()).ignore();
+        pipeline
+            .cmd("SET")
+            .arg(self.inited_key.as_str())
+            .arg("")
+            .ignore();
+
+        for (key, flag) in &flags {
+            let flag_ser = flag.to_redis_args();
+
+            if flag_ser[0].as_slice() == FAIL {
+                return Err(StoreError::FailedToSerializeFlag);
+            }
+
+            pipeline
+                .cmd("HSET")
+                .arg(self.key.as_str())
+                .arg(key.as_str())
+                .arg(flag_ser)
+                .ignore();
+        }
+
+        pipeline
+            .cmd("PUBLISH")
+            .arg(self.changes_channel.as_str())
+            .arg(ALL_CHANGED)
+            .ignore();
+
+        let res = pipeline.query(&mut *conn);
+        conn.check::<()>(res).map_err(StoreError::RedisFailure)?;
+
+        let flags: HashMap<String, Arc<FeatureFlag>> = flags
+            .into_iter()
+            .map(|(key, flag)| (key, Arc::new(flag)))
+            .collect();
+
+        self.stale.replace(flags);
+        self.initialized.store(true, Ordering::SeqCst);
+        self.written.store(true, Ordering::SeqCst);
+
+        Ok(())
     }
-}
 
-impl Store for RedisStore {
-    fn get(&self, key: &str) -> Option<FeatureFlag> {
-        // Checks individual cache
-        let cached = self.cache.get(key);
-        if cached.is_some() {
-            return cached.and_then(|f| if !f.deleted() { Some(f) } else { None });
-        };
+    // Writes the flag computed from the stored one with optimistic locking. The
+    // transaction is aborted if another writer changes the hash between the
+    // read and the write, in which case it is retried with the new value.
+    // Retries are not bounded, as a writer only loses when another one
+    // committed, and stops once a newer version than its own is stored
+    fn update<F>(&self, key: &str, f: F) -> StoreResult<()>
+    where
+        F: Fn(Option<FeatureFlag>) -> StoreResult<FeatureFlag>,
+    {
+        let mut conn = self.conn()?;
 
-        self.conn().ok().and_then(|conn| {
-            self.get_raw(key, &conn).and_then(|flag: FeatureFlag| {
-                if !flag.deleted() {
-                    self.cache.insert(key, flag.clone());
+        loop {
+            let res = cmd("WATCH").arg(self.key.as_str()).query(&mut *conn);
+            conn.check::<()>(res).map_err(StoreError::RedisFailure)?;
 
-                    Some(flag)
+            let current = self.get_raw(key, &mut conn).map_err(StoreError::RedisFailure);
+            let replacement = current.and_then(|current| f(current)).and_then(|flag| {
+                // Manually serialize to redis storable value to allow for failure handling
+                let flag_ser = (&flag).to_redis_args();
+
+                if flag_ser[0].as_slice() != FAIL {
+                    Ok((flag, flag_ser))
                 } else {
-                    None
+                    Err(StoreError::FailedToSerializeFlag)
                 }
-            })
-        })
+            });
+
+            let (flag, flag_ser) = match replacement {
+                Ok(replacement) => replacement,
+                Err(err) => {
+                    let res = cmd("UNWATCH").query(&mut *conn);
+                    let _ = conn.check::<()>(res);
+                    return Err(err);
+                }
+            };
+
+            // EXEC replies with nil when a watched key changed
+            let res = pipe()
+                .atomic()
+                .cmd("HSET")
+                .arg(self.key.as_str())
+                .arg(key)
+                .arg(flag_ser)
+                .cmd("PUBLISH")
+                .arg(self.changes_channel.as_str())
+                .arg(key)
+                .ignore()
+                .query(&mut *conn);
+            let res: Option<(u8,)> = conn.check(res).map_err(StoreError::RedisFailure)?;
+
+            if res.is_some() {
+                self.stale.insert(key, Arc::new(flag));
+
+                return Ok(());
+            }
+
+            debug!("Flag {:?} was modified concurrently, retrying", key);
+        }
     }
+}
 
-    fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
-        // Checks all cache
+/// Builds a `RedisStore` from explicit connection settings, checking them
+/// before anything is opened
+pub struct RedisStoreBuilder {
+    url: Option<String>,
+    host: String,
+    port: u16,
+    password: Option<String>,
+    database: Option<i64>,
+    tls: bool,
+    prefix: Option<String>,
+    cache_ttl: Option<Duration>,
+    max_staleness: Option<Duration>,
+    pool_size: usize,
+    checkout_timeout: Duration,
+    operation_timeout: Option<Duration>,
+}
 
-        if let Some(mut map) = self.all_cache.get(ALL_CACHE) {
-            map.retain(|_, flag| !flag.deleted());
-            return Ok(map);
+impl RedisStoreBuilder {
+    pub fn new() -> RedisStoreBuilder {
+        RedisStoreBuilder {
+            url: None,
+            host: "localhost".into(),
+            port: 6379,
+            password: None,
+            database: None,
+            tls: false,
+            prefix: None,
+            cache_ttl: None,
+            max_staleness: None,
+            pool_size: 16,
+            checkout_timeout: Duration::from_secs(5),
+            operation_timeout: None,
+        }
+    }
+
+    /// Connects to a `redis://` or `rediss://` URL instead of host and port.
+    /// A password or database set on the builder takes precedence over the
+    /// one in the URL, and `rediss://host/#insecure` skips certificate
+    /// verification
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn database(mut self, database: i64) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    /// Connects over TLS, which a `rediss://` URL also does
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Namespace for every key the store uses, `launchdarkly` by default
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// How long flags are cached locally, forever by default
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// How old the flags served while Redis is unavailable may be, unbounded
+    /// by default
+    pub fn max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    pub fn checkout_timeout(mut self, timeout: Duration) -> Self {
+        self.checkout_timeout = timeout;
+        self
+    }
+
+    pub fn operation_timeout(mut self, timeout: Duration) -> Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// Builds a store cached for the configured TTL
+    pub fn build(self) -> StoreResult<CachingStore<RedisStore>> {
+        let info = self.connection_info()?;
+
+        if self.pool_size == 0 {
+            return Err(invalid("pool size must be at least 1"));
+        }
+
+        if let Some(ref prefix) = self.prefix {
+            if prefix.is_empty() || prefix.contains(char::is_whitespace) {
+                return Err(invalid(format!("invalid key prefix {:?}", prefix)));
+            }
+        }
+
+        if self.operation_timeout == Some(Duration::new(0, 0)) {
+            return Err(invalid("operation timeout must not be zero"));
+        }
+
+        let client = Client::open(info).map_err(|err| invalid(format!("{}", err)))?;
+
+        let mut store = RedisStore::new(client, self.prefix)
+            .pool_size(self.pool_size)
+            .checkout_timeout(self.checkout_timeout)
+            .operation_timeout(self.operation_timeout);
+
+        if let Some(max_staleness) = self.max_staleness {
+            store = store.max_staleness(max_staleness);
+        }
+
+        Ok(CachingStore::new(
+            store,
+            self.cache_ttl.unwrap_or(Duration::new(0, 0)),
+        ))
+    }
+
+    fn connection_info(&self) -> StoreResult<ConnectionInfo> {
+        let mut info = match self.url {
+            Some(ref url) => url
+                .as_str()
+                .into_connection_info()
+                .map_err(|err| invalid(format!("{}: {}", url, err)))?,
+            None => {
+                if self.host.is_empty() {
+                    return Err(invalid("host must not be empty"));
+                }
+                if self.port == 0 {
+                    return Err(invalid("port must not be 0"));
+                }
+
+                ConnectionInfo {
+                    addr: ConnectionAddr::Tcp(self.host.clone(), self.port),
+                    redis: RedisConnectionInfo::default(),
+                }
+            }
         };
 
-        self.conn()?
-            .hgetall(self.key.to_string())
-            .map(|mut map: HashMap<String, FeatureFlag>| {
-                map.retain(|_, flag| !flag.deleted());
-                self.all_cache.insert(ALL_CACHE, map.clone());
-                map
-            })
-            .map_err(StoreError::RedisFailure)
+        if self.tls {
+            info.addr = match info.addr {
+                ConnectionAddr::Tcp(host, port) => ConnectionAddr::TcpTls {
+                    host: host,
+                    port: port,
+                    insecure: false,
+                },
+                ConnectionAddr::Unix(_) => {
+                    return Err(invalid("TLS is not available over unix sockets"))
+                }
+                addr => addr,
+            };
+        }
+
+        if let Some(db) = self.database {
+            if db < 0 {
+                return Err(invalid(format!("invalid database index {}", db)));
+            }
+            info.redis.db = db;
+        }
+
+        if self.password.is_some() {
+            info.redis.password = self.password.clone();
+        }
+
+        Ok(info)
     }
+}
 
-    fn delete(&self, key: &str, version: usize) -> StoreResult<()> {
-        // Ignores cache lookup
+fn invalid<S: Into<String>>(reason: S) -> StoreError {
+    StoreError::InvalidConfig(reason.into())
+}
 
-        let conn = self.conn()?;
+impl CachingStore<RedisStore> {
+    /// Drops cache entries as soon as other processes change them, see
+    /// `RedisStore::subscribe_changes`
+    pub fn subscribe_changes(self) -> Self {
+        self.inner().subscribe_changes(self.invalidator());
+        self
+    }
+}
 
-        let _: () = self.start::<()>(key, &conn)?;
+impl Drop for RedisStore {
+    fn drop(&mut self) {
+        stop_subscriber(self.subscriber.lock().unwrap().take());
+    }
+}
 
-        let res = if let Some(flag) = self.get_raw(key, &conn) {
-            if flag.version() < version {
-                let mut replacement = flag.clone();
-                replacement.delete();
-                replacement.update_version(version);
+// Waits for the subscriber to notice that it was stopped, which takes at most
+// STOP_CHECK_INTERVAL, so that its connection is closed when this returns
+fn stop_subscriber(subscriber: Option<(Arc<AtomicBool>, thread::JoinHandle<()>)>) {
+    if let Some((stop, handle)) = subscriber {
+        stop.store(true, Ordering::SeqCst);
+        handle.thread().unpark();
 
-                self.put(key, &replacement, &conn)
+        if handle.join().is_err() {
+            error!("Subscriber to flag changes panicked");
+        }
+    }
+}
+
+struct Subscriber {
+    client: Client,
+    channel: String,
+    pattern: String,
+    cache: CacheInvalidator,
+    stop: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn run(&self) {
+        while !self.stop.load(Ordering::SeqCst) {
+            if let Err(err) = self.listen() {
+                warn!("Lost subscription to flag changes, retrying: {:?}", err);
+
+                // Changes may have been missed while disconnected. Parking
+                // instead of sleeping lets the store wake the thread to stop
+                self.cache.invalidate_all();
+                thread::park_timeout(Duration::from_secs(1));
+            }
+        }
+    }
+
+    fn listen(&self) -> RedisResult<()> {
+        let mut conn = self.client.get_connection()?;
+        let mut pubsub = conn.as_pubsub();
+        pubsub.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+        pubsub.subscribe(self.channel.as_str())?;
+        pubsub.psubscribe(self.pattern.as_str())?;
+
+        while !self.stop.load(Ordering::SeqCst) {
+            let msg = match pubsub.get_message() {
+                Ok(msg) => msg,
+                Err(ref err) if err.is_timeout() => continue,
+                Err(err) => return Err(err),
+            };
+
+            // Keyspace events name the command, not the field that changed
+            let key: String = if msg.from_pattern() {
+                ALL_CHANGED.into()
             } else {
-                Err(StoreError::NewerVersionFound)
+                msg.get_payload()?
+            };
+
+            if key == ALL_CHANGED {
+                self.cache.invalidate_all();
+            } else {
+                self.cache.invalidate(key.as_str());
             }
-        } else {
-            Err(StoreError::NotFound)
+        }
+
+        Ok(())
+    }
+}
+
+impl Store for RedisStore {
+    fn get(&self, key: &str) -> Option<Arc<FeatureFlag>> {
+        let res = self.conn().and_then(|mut conn| {
+            self.get_raw(key, &mut conn)
+                .map_err(StoreError::RedisFailure)
+        });
+
+        let flag = match self.record(res) {
+            Ok(Some(flag)) => {
+                let flag = Arc::new(flag);
+                self.stale.insert(key, flag.clone());
+                flag
+            }
+            Ok(None) => {
+                self.stale.remove(key);
+                return None;
+            }
+            Err(_) => self.stale.get(key)?,
         };
 
-        self.cleanup::<()>(&conn);
-        res
+        if !flag.deleted() {
+            Some(flag)
+        } else {
+            None
+        }
     }
 
-    fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
-        // Ignores cache lookup
+    fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
+        let res = self.conn().and_then(|mut conn| {
+            let res = conn.hgetall(self.key.to_string());
+            conn.check::<HashMap<String, FeatureFlag>>(res)
+                .map_err(StoreError::RedisFailure)
+        });
 
-        let conn = self.conn()?;
+        match self.record(res) {
+            Ok(mut map) => {
+                self.stale.replace(
+                    map.iter()
+                        .map(|(key, flag)| (key.clone(), Arc::new(flag.clone())))
+                        .collect(),
+                );
 
-        let _: () = self.start::<()>(key, &conn)?;
+                map.retain(|_, flag| !flag.deleted());
+                Ok(map)
+            }
+            Err(err) => {
+                let stale = self.stale.get_all();
+                if stale.is_empty() {
+                    return Err(err);
+                }
 
-        let replacement = if let Some(e_flag) = self.get_raw(key, &conn) {
-            if e_flag.version() < flag.version() {
-                Ok(flag)
-            } else {
+                Ok(stale
+                    .into_iter()
+                    .filter(|&(_, ref flag)| !flag.deleted())
+                    .map(|(key, flag)| (key, (*flag).clone()))
+                    .collect())
+            }
+        }
+    }
+
+    fn delete(&self, key: &str, version: usize) -> StoreResult<()> {
+        self.record(self.update(key, |existing| match existing {
+            Some(flag) => {
+                if flag.version() < version {
+                    let mut replacement = flag;
+                    replacement.delete();
+                    replacement.update_version(version);
+
+                    Ok(replacement)
+                } else {
+                    Err(StoreError::NewerVersionFound)
+                }
+            }
+            None => Err(StoreError::NotFound),
+        }))
+    }
+
+    fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
+        self.record(self.update(key, |existing| match existing {
+            Some(ref e_flag) if e_flag.version() >= flag.version() => {
                 warn!(
                     "Can not overwrite flag with key {:?} in store with older version",
                     key
                 );
                 Err(StoreError::NewerVersionFound)
             }
-        } else {
-            Ok(flag)
-        }?;
+            _ => Ok(flag.clone()),
+        }))
+    }
 
-        let res = self.put(key, replacement, &conn);
+    fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
+        self.record(self.write_all(flags))
+    }
 
-        self.cleanup::<()>(&conn);
-        res
+    fn status(&self) -> StoreStatus {
+        self.status.lock().unwrap().clone()
     }
 
-    fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
-        for (key, flag) in flags {
-            self.upsert(key.as_str(), &flag);
+    // The marker is shared with every process using the same prefix, so a
+    // store that has not been initialized here may have been by a relay
+    fn initialized(&self) -> bool {
+        if self.initialized.load(Ordering::SeqCst) {
+            return true;
         }
 
-        Ok(())
+        let mut checked = self.inited_checked.lock().unwrap();
+        if checked.map_or(false, |at| at.elapsed() < INITED_CHECK_INTERVAL) {
+            return false;
+        }
+
+        let inited = self.conn().ok().map_or(false, |mut conn| {
+            let res = conn.exists(self.inited_key.as_str());
+            conn.check(res).unwrap_or(false)
+        });
+
+        if inited {
+            self.initialized.store(true, Ordering::SeqCst);
+        } else {
+            *checked = Some(Instant::now());
+        }
+
+        inited
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use feature_flag::*;
+    use std::sync::atomic::AtomicUsize;
+
+    use conformance::flag;
     use redis_store::*;
 
-    fn flag<S: Into<String>>(key: S, version: usize, deleted: bool) -> FeatureFlag {
-        FeatureFlag::new(
-            key.into(),
-            version,
-            true,
-            vec![],
-            "".into(),
-            "".into(),
-            vec![],
-            vec![],
-            VariationOrRollOut::Variation(0),
-            None,
-            vec![VariationValue::Integer(0), VariationValue::Integer(1)],
-            deleted,
-        )
+    fn open(prefix: &str) -> RedisStore {
+        let client = Client::open("redis://0.0.0.0:6379").unwrap();
+        RedisStore::new(client, Some(prefix.into()))
     }
 
-    fn dataset() -> RedisStore {
-        let store =
-            RedisStore::open("0.0.0.0".into(), 6379, None, Some(Duration::new(6, 0))).unwrap();
-        let flags = vec![flag("f1", 5, false), flag("f2", 5, true)];
+    // Every conformance check gets its own keys as they run in parallel
+    fn conformance_store() -> RedisStore {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        open(format!("conformance{}", NEXT.fetch_add(1, Ordering::SeqCst)).as_str())
+    }
 
-        for flag in flags.into_iter() {
-            store.upsert(flag.key(), &flag);
-        }
+    store_conformance_tests!(
+        conformance_store,
+        #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    );
+
+    #[test]
+    fn test_builder_rejects_invalid_config() {
+        let invalid = |builder: RedisStoreBuilder, reason: &str| match builder.build() {
+            Err(StoreError::InvalidConfig(ref err)) => assert!(err.contains(reason), "{}", err),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("expected {:?} to be rejected", reason),
+        };
 
-        store
+        invalid(
+            RedisStoreBuilder::new().url("http://localhost"),
+            "http://localhost",
+        );
+        invalid(RedisStoreBuilder::new().port(0), "port");
+        invalid(RedisStoreBuilder::new().database(-1), "database");
+        invalid(RedisStoreBuilder::new().pool_size(0), "pool size");
+        invalid(RedisStoreBuilder::new().prefix("a b"), "prefix");
+        invalid(
+            RedisStoreBuilder::new().url("redis+unix:///tmp/redis.sock").tls(true),
+            "TLS",
+        );
     }
 
     #[test]
-    fn test_does_not_return_deleted() {
-        assert!(dataset().get("f2").is_none())
+    fn test_open_rejects_invalid_url() {
+        match RedisStore::open_with_url("http://localhost".into(), None, None) {
+            Err(StoreError::InvalidConfig(ref err)) => assert!(err.contains("http://localhost")),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("expected the url to be rejected"),
+        }
     }
 
     #[test]
-    fn test_all_returns_only_not_deleted() {
-        let all = dataset().get_all().unwrap();
-        assert!(all.get("f1".into()).is_some());
-        assert!(all.get("f2".into()).is_none());
+    fn test_builder_overrides_url() {
+        let builder = RedisStoreBuilder::new()
+            .url("redis://:secret@0.0.0.0:6379/2")
+            .database(1);
+        let info = builder.connection_info().unwrap();
+
+        assert_eq!(info.redis.db, 1);
+        assert_eq!(info.redis.password, Some("secret".into()));
+        assert!(builder.build().is_ok());
     }
 
     #[test]
-    fn test_delete_does_not_delete_newer_version() {
+    fn test_builder_connects_over_tls() {
+        let tls = |builder: RedisStoreBuilder| builder.connection_info().unwrap().addr;
+
+        assert_eq!(
+            tls(RedisStoreBuilder::new().host("redis.local").tls(true)),
+            ConnectionAddr::TcpTls {
+                host: "redis.local".into(),
+                port: 6379,
+                insecure: false,
+            }
+        );
+        assert_eq!(
+            tls(RedisStoreBuilder::new().url("redis://redis.local:6380").tls(true)),
+            ConnectionAddr::TcpTls {
+                host: "redis.local".into(),
+                port: 6380,
+                insecure: false,
+            }
+        );
         assert_eq!(
-            dataset().delete("f1", 3),
-            Err(StoreError::NewerVersionFound)
-        )
+            tls(RedisStoreBuilder::new().url("rediss://redis.local/#insecure")),
+            ConnectionAddr::TcpTls {
+                host: "redis.local".into(),
+                port: 6379,
+                insecure: true,
+            }
+        );
     }
 
     #[test]
-    fn test_upsert_does_not_replace_newer_version() {
+    #[ignore = "needs a Redis server with TLS at 0.0.0.0:6380"]
+    fn test_connects_over_tls() {
+        let store = RedisStoreBuilder::new()
+            .url("rediss://0.0.0.0:6380/#insecure")
+            .prefix("tls")
+            .build()
+            .unwrap();
+
+        store.init(HashMap::new()).unwrap();
+        assert!(store.initialized());
+        assert_eq!(store.status(), StoreStatus::Available);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_initialized_by_another_store() {
+        let reader = open("inited");
+        let mut conn = reader.conn().unwrap();
+        let _: RedisResult<()> = conn.del(reader.inited_key.as_str());
+        assert!(!reader.initialized());
+
+        open("inited").init(HashMap::new()).unwrap();
+        assert!(!reader.initialized());
+
+        *reader.inited_checked.lock().unwrap() = None;
+        assert!(reader.initialized());
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_subscriber_invalidates_cache() {
+        fn open() -> CachingStore<RedisStore> {
+            RedisStore::open(
+                "0.0.0.0".into(),
+                6379,
+                Some("subscribed".into()),
+                Some(Duration::from_secs(60)),
+            )
+            .unwrap()
+        }
+
+        let writer = open();
+        writer.init(HashMap::new()).unwrap();
+        writer.upsert("f1", &flag("f1", 1, false)).unwrap();
+
+        let reader = open().subscribe_changes();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(reader.get("f1").unwrap().version(), 1);
+
+        writer.upsert("f1", &flag("f1", 2, false)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(reader.get("f1").unwrap().version(), 2);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_drop_stops_subscriber() {
+        let store = open("dropped");
+        store.subscribe_changes(CachingStore::forever(open("dropped")).invalidator());
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        drop(store);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_serves_stale_flags_while_unavailable() {
+        let store = RedisStoreBuilder::new()
+            .port(1)
+            .checkout_timeout(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        store.inner().stale.insert("f1", Arc::new(flag("f1", 1, false)));
+        store.inner().stale.insert("f2", Arc::new(flag("f2", 1, true)));
+
+        assert_eq!(store.get("f1").unwrap().version(), 1);
+        assert!(store.get("f2").is_none());
         assert_eq!(
-            dataset().upsert("f1", &flag("f1", 3, false)),
-            Err(StoreError::NewerVersionFound)
-        )
+            store.get_all().unwrap().keys().collect::<Vec<_>>(),
+            vec!["f1"]
+        );
+
+        match store.status() {
+            StoreStatus::Unavailable { .. } => (),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_restores_flags_after_recovery() {
+        let store = open("restored");
+        let mut flags = HashMap::new();
+        flags.insert("f1".to_string(), flag("f1", 1, false));
+        store.init(flags).unwrap();
+
+        // Redis restarts empty while the store can not reach it
+        store.unavailable(&"connection refused");
+        {
+            let mut conn = store.conn().unwrap();
+            let _: RedisResult<()> = conn.del(vec![store.key.as_str(), store.inited_key.as_str()]);
+        }
+
+        assert!(store.get("f2").is_none());
+        assert_eq!(store.status(), StoreStatus::Available);
+
+        let mut conn = store.conn().unwrap();
+        let all: HashMap<String, FeatureFlag> = conn.hgetall(store.key.as_str()).unwrap();
+        assert_eq!(all.keys().collect::<Vec<_>>(), vec!["f1"]);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_readers_do_not_restore_flags() {
+        let mut flags = HashMap::new();
+        flags.insert("f1".to_string(), flag("f1", 1, false));
+        open("unowned").init(flags).unwrap();
+
+        let reader = open("unowned");
+        assert!(reader.initialized());
+        assert!(reader.get("f1").is_some());
+
+        reader.unavailable(&"connection refused");
+        {
+            let mut conn = reader.conn().unwrap();
+            let _: RedisResult<()> = conn.del(vec![reader.key.as_str(), reader.inited_key.as_str()]);
+        }
+
+        assert!(reader.get("f2").is_none());
+        assert_eq!(reader.status(), StoreStatus::Available);
+
+        let mut conn = reader.conn().unwrap();
+        let inited: bool = conn.exists(reader.inited_key.as_str()).unwrap();
+        assert!(!inited);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_does_not_restore_expired_flags() {
+        let store = open("expired").max_staleness(Duration::from_millis(20));
+        let mut flags = HashMap::new();
+        flags.insert("f1".to_string(), flag("f1", 1, false));
+        store.init(flags).unwrap();
+        thread::sleep(Duration::from_millis(30));
+
+        store.unavailable(&"connection refused");
+        {
+            let mut conn = store.conn().unwrap();
+            let _: RedisResult<()> = conn.del(vec![store.key.as_str(), store.inited_key.as_str()]);
+        }
+
+        assert!(store.get("f2").is_none());
+
+        let mut conn = store.conn().unwrap();
+        let inited: bool = conn.exists(store.inited_key.as_str()).unwrap();
+        assert!(!inited);
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_unreadable_flags_are_not_overwritten() {
+        let store = open("unreadable");
+        store.init(HashMap::new()).unwrap();
+        {
+            let mut conn = store.conn().unwrap();
+            let _: () = conn.hset(store.key.as_str(), "f1", "not a flag").unwrap();
+        }
+
+        match store.upsert("f1", &flag("f1", 1, false)) {
+            Err(StoreError::RedisFailure(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let mut conn = store.conn().unwrap();
+        let raw: String = conn.hget(store.key.as_str(), "f1").unwrap();
+        assert_eq!(raw, "not a flag");
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_connections_are_shared_between_threads() {
+        let store = Arc::new(open("shared").pool_size(2));
+        store.init(HashMap::new()).unwrap();
+
+        let handles = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                ::std::thread::spawn(move || store.get_all().map(|_| ()))
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Ok(()));
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at 0.0.0.0:6379"]
+    fn test_concurrent_upserts_keep_highest_version() {
+        open("concurrency").init(HashMap::new()).unwrap();
+
+        let handles = (1..41)
+            .map(|version| {
+                ::std::thread::spawn(move || {
+                    let store = open("concurrency");
+                    (version, store.upsert("f1", &flag("f1", version, false)))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            match handle.join().unwrap() {
+                (_, Ok(())) | (_, Err(StoreError::NewerVersionFound)) => (),
+                (version, Err(err)) => panic!("version {} failed with {:?}", version, err),
+            }
+        }
+
+        let store = open("concurrency");
+        let mut conn = store.conn().unwrap();
+        let stored = store.get_raw("f1", &mut conn).unwrap().unwrap();
+        assert_eq!(stored.version(), 40);
     }
 }
@@ -1,11 +1,13 @@
-use reqwest::{Client, Error as ReqError};
-use reqwest::header::{Authorization, Headers, UserAgent};
+use reqwest::{Client, Error as ReqError, Response, StatusCode};
+use reqwest::header::{Authorization, ETag, EntityTag, Headers, IfNoneMatch};
 use serde::Deserialize;
 
 use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use feature_flag::FeatureFlag;
-use VERSION;
+use http::{HttpConfig, HttpError};
 
 const FLAG_PATH: &'static str = "/sdk/latest-flags";
 
@@ -15,6 +17,7 @@ pub type RequestResult<T> = Result<T, RequestError>;
 pub enum RequestError {
     Testing,
     HTTPFailure(ReqError),
+    HTTPStatus(StatusCode),
     ParseFailure(ReqError),
 }
 
@@ -22,20 +25,36 @@ pub struct Requestor {
     client: Client,
     base_uri: String,
     key: String,
+    etag: Mutex<Option<EntityTag>>,
 }
 
 // TODO: Does not implement any caching, retries, or backoff
 
 impl Requestor {
-    pub fn new<S, T>(base_uri: S, key: T) -> Requestor
+    /// Sends requests with the default HTTP settings and a 10 second timeout
+    pub fn new<S, T>(base_uri: S, key: T) -> Result<Requestor, HttpError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let base_uri = base_uri.into();
+        let client = HttpConfig::default().client(base_uri.as_str(), Duration::from_secs(10))?;
+
+        Ok(Requestor::with_client(base_uri, key, client))
+    }
+
+    /// Sends requests with a client built by `HttpConfig::client`, carrying
+    /// the timeout and HTTP settings
+    pub fn with_client<S, T>(base_uri: S, key: T, client: Client) -> Requestor
     where
         S: Into<String>,
         T: Into<String>,
     {
         Requestor {
-            client: Client::new(),
+            client: client,
             base_uri: base_uri.into(),
             key: key.into(),
+            etag: Mutex::new(None),
         }
     }
 
@@ -44,6 +63,37 @@ impl Requestor {
         self.request(self.base_uri.to_string() + FLAG_PATH)
     }
 
+    /// Fetches all flags unless they are unchanged since the ETag last passed
+    /// to `commit_etag`, in which case `None` is returned. The flags come with
+    /// the response's ETag, to be committed once they are stored
+    pub fn get_all_if_modified(
+        &self,
+    ) -> RequestResult<Option<(HashMap<String, FeatureFlag>, Option<EntityTag>)>> {
+        let mut headers = self.headers();
+
+        if let Some(ref tag) = *self.etag.lock().unwrap() {
+            headers.set(IfNoneMatch::Items(vec![tag.clone()]));
+        }
+
+        let mut res = self.send(self.base_uri.to_string() + FLAG_PATH, headers)?;
+
+        if res.status() == StatusCode::NotModified {
+            return Ok(None);
+        }
+
+        let flags = res.json().map_err(RequestError::ParseFailure)?;
+        let etag = res.headers().get::<ETag>().map(|tag| tag.0.clone());
+
+        Ok(Some((flags, etag)))
+    }
+
+    /// Sends `etag` with later calls to `get_all_if_modified`. Only commit it
+    /// after storing the flags it came with, or they would never be fetched
+    /// again
+    pub fn commit_etag(&self, etag: Option<EntityTag>) {
+        *self.etag.lock().unwrap() = etag;
+    }
+
     pub fn get(&self, key: &str) -> RequestResult<Option<FeatureFlag>> {
         // Ok(None)
         self.request(self.base_uri.to_string() + FLAG_PATH + "/" + key)
@@ -54,18 +104,127 @@ impl Requestor {
     where
         for<'de> T: Deserialize<'de>,
     {
+        let headers = self.headers();
+
+        // Err(RequestError::Testing)
+        let mut inter = self.send(endpoint, headers)?;
+        // panic!("{:?}", inter.text());
+        // panic!("{:?}", inter.json::<HashMap<String, FeatureFlag>>());
+        inter.json().map_err(RequestError::ParseFailure)
+    }
+
+    fn headers(&self) -> Headers {
         let mut headers = Headers::new();
         headers.set(Authorization(self.key.clone()));
-        headers.set(UserAgent::new("RustTest/".to_string() + VERSION));
+        headers
+    }
 
-        // Err(RequestError::Testing)
-        let mut inter = self.client
+    fn send<S: Into<String>>(&self, endpoint: S, headers: Headers) -> RequestResult<Response> {
+        let res = self.client
             .get(endpoint.into().as_str())
             .headers(headers)
             .send()
             .map_err(RequestError::HTTPFailure)?;
-        // panic!("{:?}", inter.text());
-        // panic!("{:?}", inter.json::<HashMap<String, FeatureFlag>>());
-        inter.json().map_err(RequestError::ParseFailure)
+
+        match res.status() {
+            status if status.is_success() || status == StatusCode::NotModified => Ok(res),
+            status => Err(RequestError::HTTPStatus(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use request::*;
+    use test_server::serve;
+
+    #[test]
+    fn test_skips_unmodified_flags() {
+        let (addr, requests) = serve(vec![
+            "HTTP/1.1 200 OK\r\n\
+             ETag: \"v1\"\r\n\
+             Content-Length: 2\r\n\
+             Connection: close\r\n\r\n{}",
+            "HTTP/1.1 304 Not Modified\r\n\
+             Connection: close\r\n\r\n",
+        ]);
+        let req = Requestor::new(addr, "key").unwrap();
+
+        let (flags, etag) = req.get_all_if_modified().unwrap().unwrap();
+        assert_eq!(flags, HashMap::new());
+        req.commit_etag(etag);
+        assert_eq!(req.get_all_if_modified().unwrap(), None);
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+    }
+
+    #[test]
+    fn test_times_out_unresponsive_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+
+        // Accepts connections but never responds
+        thread::spawn(move || {
+            let held = listener.incoming().collect::<Vec<_>>();
+            drop(held);
+        });
+
+        let client = HttpConfig::default()
+            .client(addr.as_str(), Duration::from_millis(200))
+            .unwrap();
+        let req = Requestor::with_client(addr, "key", client);
+        let start = ::std::time::Instant::now();
+
+        match req.get_all() {
+            Err(RequestError::HTTPFailure(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_sends_through_proxy() {
+        let (addr, requests) = serve(vec![
+            "HTTP/1.1 200 OK\r\n\
+             Content-Length: 2\r\n\
+             Connection: close\r\n\r\n{}",
+        ]);
+        let http = HttpConfig {
+            proxy: Some(addr.replace("http://", "http://user:pass@")),
+            headers: vec![("X-Team".into(), "flags".into())],
+            ..HttpConfig::default()
+        };
+        let client = http
+            .client("http://flags.invalid", Duration::from_secs(5))
+            .unwrap();
+        let req = Requestor::with_client("http://flags.invalid", "key", client);
+
+        req.get_all().unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("get http://flags.invalid/sdk/latest-flags "));
+        assert!(requests[0].contains("proxy-authorization: basic dxnlcjpwyxnz"));
+        assert!(requests[0].contains("x-team: flags"));
+        assert!(requests[0].contains("user-agent: rusttest/"));
+    }
+
+    #[test]
+    fn test_reports_error_status() {
+        let (addr, _) = serve(vec![
+            "HTTP/1.1 500 Internal Server Error\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+        ]);
+        let req = Requestor::new(addr, "key").unwrap();
+
+        match req.get_all_if_modified() {
+            Err(RequestError::HTTPStatus(StatusCode::InternalServerError)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
     }
 }
@@ -0,0 +1,246 @@
+use rusqlite::{params, Connection, Error as SqliteError, Transaction, TransactionBehavior};
+use serde_json;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use feature_flag::FeatureFlag;
+use store::{Store, StoreError, StoreResult};
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS flags (
+        key TEXT PRIMARY KEY,
+        deleted INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS metadata (
+        key TEXT PRIMARY KEY
+    );
+";
+const INITED: &'static str = "$inited";
+
+/// Persists flags to a SQLite database on local disk, so that they survive
+/// restarts without an external service. Deleted flags are kept as
+/// tombstones so that older updates can not bring them back
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<SqliteStore> {
+        SqliteStore::with_connection(Connection::open(path).map_err(failure)?)
+    }
+
+    pub fn open_in_memory() -> StoreResult<SqliteStore> {
+        SqliteStore::with_connection(Connection::open_in_memory().map_err(failure)?)
+    }
+
+    fn with_connection(conn: Connection) -> StoreResult<SqliteStore> {
+        // Waits for other processes writing to the same file instead of failing
+        conn.busy_timeout(Duration::from_secs(5)).map_err(failure)?;
+        conn.execute_batch(SCHEMA).map_err(failure)?;
+
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<Connection> {
+        match self.conn.lock() {
+            Ok(guard) => guard,
+            Err(err) => {
+                error!("Lock for SQLite connection failed due to poisoning");
+                panic!("{:?}", err)
+            }
+        }
+    }
+
+    // Writes the flag computed from the stored one. The transaction takes the
+    // write lock up front, so that no other writer can change the flag between
+    // the read and the write
+    fn update<F>(&self, key: &str, f: F) -> StoreResult<()>
+    where
+        F: Fn(Option<FeatureFlag>) -> StoreResult<FeatureFlag>,
+    {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(failure)?;
+
+        // Dropping the transaction on error rolls it back
+        let flag = f(get_raw(&tx, key)?)?;
+        put(&tx, key, &flag)?;
+
+        tx.commit().map_err(failure)
+    }
+}
+
+fn failure(err: SqliteError) -> StoreError {
+    StoreError::SqliteFailure(format!("{}", err))
+}
+
+fn get_raw(conn: &Connection, key: &str) -> StoreResult<Option<FeatureFlag>> {
+    let res = conn.query_row("SELECT data FROM flags WHERE key = ?1", params![key], |row| {
+        row.get::<_, String>(0)
+    });
+
+    match res {
+        Ok(data) => serde_json::from_str(data.as_str())
+            .map(Some)
+            .map_err(|err| StoreError::SqliteFailure(format!("{}: {}", key, err))),
+        Err(SqliteError::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(failure(err)),
+    }
+}
+
+fn put(tx: &Transaction, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
+    let data = serde_json::to_string(flag).map_err(|_| StoreError::FailedToSerializeFlag)?;
+
+    tx.execute(
+        "INSERT OR REPLACE INTO flags (key, deleted, data) VALUES (?1, ?2, ?3)",
+        params![key, flag.deleted(), data],
+    )
+    .map(|_| ())
+    .map_err(failure)
+}
+
+impl Store for SqliteStore {
+    fn get(&self, key: &str) -> Option<Arc<FeatureFlag>> {
+        match get_raw(&self.conn(), key) {
+            Ok(Some(flag)) => {
+                if !flag.deleted() {
+                    Some(Arc::new(flag))
+                } else {
+                    None
+                }
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Failed to read flag {:?}: {:?}", key, err);
+                None
+            }
+        }
+    }
+
+    fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT key, data FROM flags WHERE deleted = 0")
+            .map_err(failure)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(failure)?;
+
+        let mut flags = HashMap::new();
+        for row in rows {
+            let (key, data) = row.map_err(failure)?;
+            let flag = serde_json::from_str(data.as_str())
+                .map_err(|err| StoreError::SqliteFailure(format!("{}: {}", key, err)))?;
+
+            flags.insert(key, flag);
+        }
+
+        Ok(flags)
+    }
+
+    fn delete(&self, key: &str, version: usize) -> StoreResult<()> {
+        self.update(key, |existing| match existing {
+            Some(flag) => {
+                if flag.version() < version {
+                    let mut replacement = flag;
+                    replacement.delete();
+                    replacement.update_version(version);
+
+                    Ok(replacement)
+                } else {
+                    Err(StoreError::NewerVersionFound)
+                }
+            }
+            None => Err(StoreError::NotFound),
+        })
+    }
+
+    fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()> {
+        // Tombstones are compared too, so a stale update can not revive a flag
+        self.update(key, |existing| match existing {
+            Some(ref e_flag) if e_flag.version() >= flag.version() => {
+                warn!(
+                    "Can not overwrite flag with key {:?} in store with older version",
+                    key
+                );
+                Err(StoreError::NewerVersionFound)
+            }
+            _ => Ok(flag.clone()),
+        })
+    }
+
+    fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(failure)?;
+
+        // Tombstones go too, as with MemStore and RedisStore
+        tx.execute("DELETE FROM flags", []).map_err(failure)?;
+        for (key, flag) in &flags {
+            put(&tx, key.as_str(), flag)?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO metadata (key) VALUES (?1)",
+            params![INITED],
+        )
+        .map_err(failure)?;
+
+        tx.commit().map_err(failure)
+    }
+
+    fn initialized(&self) -> bool {
+        let res = self.conn().query_row(
+            "SELECT COUNT(*) FROM metadata WHERE key = ?1",
+            params![INITED],
+            |row| row.get::<_, i64>(0),
+        );
+
+        res.map(|count| count > 0).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use conformance::flag;
+    use sqlite_store::*;
+
+    store_conformance_tests!(|| SqliteStore::open_in_memory().unwrap());
+
+    #[test]
+    fn test_survives_reopening() {
+        static DATABASES: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "dark_sqlite_store_{}_{}.db",
+            process::id(),
+            DATABASES.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        {
+            let mut flags = HashMap::new();
+            flags.insert("f1".to_string(), flag("f1", 1, false));
+            SqliteStore::open(&path).unwrap().init(flags).unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.initialized());
+        assert_eq!(store.get("f1").unwrap().version(), 1);
+
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event_type: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    pub fn new() -> Event {
+        Event::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.event_type.is_none() && self.data.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseResult {
+    Comment,
+    Next,
+    Dispatch,
+    SetRetry(Duration),
+}
+
+// Parses a single line of a text/event-stream body into the event being built,
+// following https://html.spec.whatwg.org/multipage/server-sent-events.html
+pub fn parse_line(line: &str, event: &mut Event) -> ParseResult {
+    let line = line.trim_right_matches(|c| c == '\n' || c == '\r');
+
+    if line.is_empty() {
+        // Data fields are joined by newlines, so the final one is dropped
+        if event.data.ends_with('\n') {
+            event.data.pop();
+        }
+
+        return ParseResult::Dispatch;
+    }
+
+    if line.starts_with(':') {
+        return ParseResult::Comment;
+    }
+
+    let (field, value) = match line.find(':') {
+        Some(i) => {
+            let value = &line[i + 1..];
+            let value = if value.starts_with(' ') {
+                &value[1..]
+            } else {
+                value
+            };
+
+            (&line[..i], value)
+        }
+        None => (line, ""),
+    };
+
+    match field {
+        "event" => event.event_type = Some(value.into()),
+        "data" => {
+            event.data.push_str(value);
+            event.data.push('\n');
+        }
+        "id" => {
+            if !value.contains('\0') {
+                event.id = Some(value.into())
+            }
+        }
+        "retry" => {
+            if let Ok(millis) = value.parse::<u64>() {
+                return ParseResult::SetRetry(Duration::from_millis(millis));
+            }
+        }
+        _ => (),
+    }
+
+    ParseResult::Next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> (Event, Vec<ParseResult>) {
+        let mut event = Event::new();
+        let results = lines.iter().map(|l| parse_line(l, &mut event)).collect();
+        (event, results)
+    }
+
+    #[test]
+    fn test_parses_event() {
+        let (event, results) = parse(&["event: put\n", "id: 7\n", "data: {}\n", "\n"]);
+
+        assert_eq!(event.event_type, Some("put".into()));
+        assert_eq!(event.id, Some("7".into()));
+        assert_eq!(event.data, "{}");
+        assert_eq!(results.last(), Some(&ParseResult::Dispatch));
+    }
+
+    #[test]
+    fn test_joins_data_lines() {
+        let (event, _) = parse(&["data:first\r\n", "data: second\r\n", "\r\n"]);
+        assert_eq!(event.data, "first\nsecond");
+    }
+
+    #[test]
+    fn test_comments_and_retry() {
+        let (event, results) = parse(&[":heartbeat\n", "retry: 2500\n", "retry: soon\n"]);
+
+        assert!(event.is_empty());
+        assert_eq!(
+            results,
+            vec![
+                ParseResult::Comment,
+                ParseResult::SetRetry(Duration::from_millis(2500)),
+                ParseResult::Next,
+            ]
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt::Debug;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Health of the thread keeping the store up to date
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataSourceStatus {
+    /// No flags have been received yet
+    Initializing,
+    /// Flags have been received and the source is connected
+    Valid,
+    /// Updates stopped flowing after the store was initialized
+    Interrupted {
+        last_error: String,
+        since: SystemTime,
+    },
+    /// The source has stopped and will not resume, or the client is offline
+    Off,
+}
+
+#[derive(Debug)]
+struct TrackerState {
+    status: DataSourceStatus,
+    subscribers: Vec<Sender<DataSourceStatus>>,
+    // Whether an uninitialized store was reported in the current status
+    store_not_initialized: bool,
+}
+
+/// Shared by a data source and the client to report and observe its status.
+/// Clones share the same state
+#[derive(Clone, Debug)]
+pub struct StatusTracker {
+    state: Arc<Mutex<TrackerState>>,
+    initialized: Initialized,
+}
+
+impl Default for StatusTracker {
+    fn default() -> StatusTracker {
+        StatusTracker {
+            state: Arc::new(Mutex::new(TrackerState {
+                status: DataSourceStatus::Initializing,
+                subscribers: vec![],
+                store_not_initialized: false,
+            })),
+            initialized: Initialized::new(),
+        }
+    }
+}
+
+impl StatusTracker {
+    pub fn new() -> StatusTracker {
+        StatusTracker::default()
+    }
+
+    pub fn status(&self) -> DataSourceStatus {
+        self.state.lock().unwrap().status.clone()
+    }
+
+    /// Set the first time the source becomes valid
+    pub fn initialized(&self) -> &Initialized {
+        &self.initialized
+    }
+
+    /// Receives every subsequent status change. Dropping the receiver
+    /// unsubscribes it
+    pub fn subscribe(&self) -> Receiver<DataSourceStatus> {
+        let (tx, rx) = channel();
+        self.state.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    pub fn valid(&self) {
+        self.initialized.set();
+        self.update(|_| DataSourceStatus::Valid);
+    }
+
+    // Failures before the first payload leave the source initializing, and
+    // repeated failures keep the time the interruption started
+    pub fn interrupted<E: Debug>(&self, err: E) {
+        let last_error = format!("{:?}", err);
+
+        self.update(|status| match *status {
+            DataSourceStatus::Initializing => DataSourceStatus::Initializing,
+            DataSourceStatus::Interrupted { since, .. } => DataSourceStatus::Interrupted {
+                last_error: last_error,
+                since: since,
+            },
+            _ => DataSourceStatus::Interrupted {
+                last_error: last_error,
+                since: SystemTime::now(),
+            },
+        });
+    }
+
+    pub fn off(&self) {
+        self.update(|_| DataSourceStatus::Off);
+    }
+
+    /// Reported while a store populated by another process, such as a relay,
+    /// has not been initialized yet. Only the first report is logged until the
+    /// status changes
+    pub fn store_not_initialized(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.store_not_initialized {
+            warn!(
+                "Store has not been initialized by the relay, evaluations will return defaults until it is"
+            );
+            state.store_not_initialized = true;
+        }
+    }
+
+    fn update<F: FnOnce(&DataSourceStatus) -> DataSourceStatus>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        let status = f(&state.status);
+
+        if status != state.status {
+            if state.store_not_initialized && status == DataSourceStatus::Valid {
+                info!("Store has been initialized by the relay");
+            }
+
+            state
+                .subscribers
+                .retain(|subscriber| subscriber.send(status.clone()).is_ok());
+            state.status = status;
+            state.store_not_initialized = false;
+        }
+    }
+}
+
+/// Set by a data source once it has written its first full set of flags to
+/// the store. Clones share the same signal
+#[derive(Clone, Debug, Default)]
+pub struct Initialized {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Initialized {
+    pub fn new() -> Initialized {
+        Initialized::default()
+    }
+
+    pub fn set(&self) {
+        let (ref lock, ref cvar) = *self.inner;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    pub fn is_set(&self) -> bool {
+        let (ref lock, _) = *self.inner;
+        *lock.lock().unwrap()
+    }
+
+    /// Blocks until the signal is set or the timeout elapses, returning
+    /// whether it was set
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (ref lock, ref cvar) = *self.inner;
+        let deadline = Instant::now() + timeout;
+        let mut set = lock.lock().unwrap();
+
+        while !*set {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            set = cvar.wait_timeout(set, deadline - now).unwrap().0;
+        }
+
+        true
+    }
+}
+
+/// Asks data source threads to stop, waking them from their waits between
+/// reloads or reconnections. Clones share the same signal
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    // Set once, like the signal of a first payload
+    signal: Initialized,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn shutdown(&self) {
+        self.signal.set();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.signal.is_set()
+    }
+
+    /// Sleeps for `timeout` unless shut down first, returning whether it was
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        self.signal.wait(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use status::*;
+
+    #[test]
+    fn test_status_transitions() {
+        let tracker = StatusTracker::new();
+        let updates = tracker.subscribe();
+
+        tracker.interrupted("refused");
+        assert_eq!(tracker.status(), DataSourceStatus::Initializing);
+
+        tracker.valid();
+        assert!(tracker.initialized().is_set());
+
+        tracker.interrupted("reset");
+        let since = match tracker.status() {
+            DataSourceStatus::Interrupted { since, .. } => since,
+            status => panic!("unexpected status {:?}", status),
+        };
+
+        tracker.interrupted("refused");
+        assert_eq!(
+            tracker.status(),
+            DataSourceStatus::Interrupted {
+                last_error: "\"refused\"".into(),
+                since: since,
+            }
+        );
+
+        tracker.off();
+
+        let kinds: Vec<_> = updates
+            .try_iter()
+            .map(|status| match status {
+                DataSourceStatus::Initializing => "initializing",
+                DataSourceStatus::Valid => "valid",
+                DataSourceStatus::Interrupted { .. } => "interrupted",
+                DataSourceStatus::Off => "off",
+            })
+            .collect();
+        assert_eq!(kinds, vec!["valid", "interrupted", "interrupted", "off"]);
+    }
+
+    #[test]
+    fn test_uninitialized_store_is_reported_once_per_status() {
+        let tracker = StatusTracker::new();
+        let updates = tracker.subscribe();
+
+        tracker.store_not_initialized();
+        tracker.store_not_initialized();
+        assert!(tracker.state.lock().unwrap().store_not_initialized);
+        assert_eq!(tracker.status(), DataSourceStatus::Initializing);
+        assert!(updates.try_recv().is_err());
+
+        tracker.valid();
+        assert!(!tracker.state.lock().unwrap().store_not_initialized);
+    }
+
+    #[test]
+    fn test_wait_times_out() {
+        let initialized = Initialized::new();
+
+        assert!(!initialized.wait(Duration::from_millis(20)));
+        assert!(!initialized.is_set());
+    }
+
+    #[test]
+    fn test_wait_wakes_when_set() {
+        let initialized = Initialized::new();
+        let signal = initialized.clone();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            signal.set();
+        });
+
+        assert!(initialized.wait(Duration::from_secs(5)));
+        assert!(initialized.is_set());
+    }
+}
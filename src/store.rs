@@ -1,6 +1,8 @@
 use redis::RedisError;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use feature_flag::FeatureFlag;
 
@@ -9,16 +11,46 @@ pub type StoreResult<T> = Result<T, StoreError>;
 #[derive(Debug, PartialEq)]
 pub enum StoreError {
     FailedToSerializeFlag,
+    /// Rejected by a store builder or `RedisStore::open_with_url`, with the
+    /// reason
+    InvalidConfig(String),
+    /// No longer returned, invalid settings are reported as `InvalidConfig`
+    #[deprecated(note = "invalid settings are reported as `InvalidConfig`")]
     InvalidRedisConfig,
     NewerVersionFound,
     NotFound,
+    PoolTimeout,
+    ReadOnly,
     RedisFailure(RedisError),
+    SqliteFailure(String),
+}
+
+/// Whether the persistent store could be reached by the last operation
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoreStatus {
+    Available,
+    Unavailable {
+        last_error: String,
+        since: SystemTime,
+    },
 }
 
 pub trait Store: Sync + Send {
-    fn get(&self, key: &str) -> Option<FeatureFlag>;
+    fn get(&self, key: &str) -> Option<Arc<FeatureFlag>>;
     fn get_all(&self) -> StoreResult<HashMap<String, FeatureFlag>>;
     fn delete(&self, key: &str, version: usize) -> StoreResult<()>;
     fn upsert(&self, key: &str, flag: &FeatureFlag) -> StoreResult<()>;
+    /// Replaces every stored flag with the given set, removing flags that are
+    /// not part of it. Tombstones of deleted flags are removed as well, the
+    /// new set is the complete state of the data source
     fn init(&self, flags: HashMap<String, FeatureFlag>) -> StoreResult<()>;
+    /// Whether `init` has completed at least once. Stores that do not track
+    /// it are assumed to be initialized
+    fn initialized(&self) -> bool {
+        true
+    }
+    /// Stores held in memory are always available
+    fn status(&self) -> StoreStatus {
+        StoreStatus::Available
+    }
 }
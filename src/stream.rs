@@ -1,28 +1,39 @@
-use eventsource::event::Event;
-use eventsource::reqwest::{Client, Error as EventSourceError};
-use reqwest::Url;
-use reqwest::header::{Authorization, Headers, UserAgent};
+use rand;
+use reqwest::{Client, Error as ReqError, Response, StatusCode, Url};
+use reqwest::header::{Authorization, Headers};
 use serde_json;
 use serde_json::Error as ParseError;
 
+use std::cmp;
 use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
+use change::FlagChanges;
 use feature_flag::FeatureFlag;
+use http::HttpConfig;
 use request::{RequestError, Requestor};
+use sse::{parse_line, Event, ParseResult};
+use status::{Shutdown, StatusTracker};
 use store::{Store, StoreError};
-use VERSION;
 
 #[derive(Debug)]
 enum StreamError {
-    EventSource(EventSourceError),
+    Closed,
+    Connection(ReqError),
     FlagNotFound,
+    HttpStatus(StatusCode),
+    InvalidPath(String),
     ParseData(ParseError),
     ParseType,
+    Read(IoError),
     Request(RequestError),
     Storage(StoreError),
+    TimedOut(Duration),
+    Unauthorized(StatusCode),
 }
 
 #[derive(Debug)]
@@ -30,6 +41,7 @@ enum StreamEventType {
     Put,
     Patch,
     Delete,
+    IndirectPut,
     IndirectPatch,
 }
 
@@ -41,6 +53,7 @@ impl FromStr for StreamEventType {
             "put" => Ok(StreamEventType::Put),
             "patch" => Ok(StreamEventType::Patch),
             "delete" => Ok(StreamEventType::Delete),
+            "indirect/put" => Ok(StreamEventType::IndirectPut),
             "indirect/patch" => Ok(StreamEventType::IndirectPatch),
             _ => Err(StreamError::ParseType),
         }
@@ -54,27 +67,129 @@ struct Patch {
     flag: FeatureFlag,
 }
 
-impl Patch {
-    pub fn key(&self) -> &str {
-        &self.path.as_str()[1..]
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct Delete {
     path: String,
     version: usize,
 }
 
-impl Delete {
-    pub fn key(&self) -> &str {
-        &self.path.as_str()[1..]
+#[derive(Debug, PartialEq)]
+enum DataKind {
+    Flags,
+    Segments,
+}
+
+// Splits a path such as /flags/my-flag into the kind of data and its key.
+// Paths without a kind, such as /my-flag, refer to flags
+fn parse_path(path: &str) -> Result<(DataKind, &str), StreamError> {
+    let invalid = || StreamError::InvalidPath(path.to_string());
+
+    if !path.starts_with('/') {
+        return Err(invalid());
     }
+
+    let (kind, key) = match path[1..].find('/') {
+        Some(i) => match &path[1..i + 1] {
+            "flags" => (DataKind::Flags, &path[i + 2..]),
+            "segments" => (DataKind::Segments, &path[i + 2..]),
+            _ => return Err(invalid()),
+        },
+        None => (DataKind::Flags, &path[1..]),
+    };
+
+    if key.is_empty() || key.contains('/') {
+        return Err(invalid());
+    }
+
+    Ok((kind, key))
+}
+
+// Segments are not stored yet, so updates to them are skipped
+fn flag_key(path: &str) -> Result<Option<&str>, StreamError> {
+    match parse_path(path)? {
+        (DataKind::Flags, key) => Ok(Some(key)),
+        (DataKind::Segments, key) => {
+            debug!("Ignoring update to segment {:?}", key);
+            Ok(None)
+        }
+    }
+}
+
+/// Jittered exponential backoff between stream reconnection attempts. The
+/// delay starts over once a connection has stayed up for `reset_after`
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    reset_after: Duration,
+    attempts: u32,
+    connected_at: Option<Instant>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(
+            Duration::from_secs(1),
+            Duration::from_secs(30),
+            Duration::from_secs(60),
+        )
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, reset_after: Duration) -> Backoff {
+        Backoff {
+            initial: initial,
+            max: max,
+            reset_after: reset_after,
+            attempts: 0,
+            connected_at: None,
+        }
+    }
+
+    // The server may adjust the initial delay with a retry field
+    fn set_initial(&mut self, initial: Duration) {
+        self.initial = cmp::min(initial, self.max);
+    }
+
+    fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= self.reset_after {
+                self.attempts = 0;
+            }
+        }
+
+        let initial = duration_millis(self.initial);
+        let max = duration_millis(self.max);
+        let delay = cmp::min(
+            max,
+            initial.saturating_mul(1u64 << cmp::min(self.attempts, 32)),
+        );
+        self.attempts = self.attempts.saturating_add(1);
+
+        // Randomly shorten the delay by up to half to spread out reconnections
+        let jitter = (rand::random::<f64>() * (delay / 2) as f64) as u64;
+        Duration::from_millis(delay - jitter)
+    }
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000
 }
 
 pub struct Streaming<S: Store + 'static> {
     store: Arc<S>,
     req: Arc<Requestor>,
+    backoff: Backoff,
+    read_timeout: Duration,
+    http: HttpConfig,
+    status: StatusTracker,
+    changes: FlagChanges,
+    shutdown: Shutdown,
 }
 
 impl<S: Store> Streaming<S> {
@@ -82,30 +197,198 @@ impl<S: Store> Streaming<S> {
         Streaming {
             store: store,
             req: req,
+            backoff: Backoff::default(),
+            read_timeout: Duration::from_secs(300),
+            http: HttpConfig::default(),
+            status: StatusTracker::new(),
+            changes: FlagChanges::new(),
+            shutdown: Shutdown::new(),
         }
     }
 
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Maximum time to wait without receiving any data before the connection
+    /// is considered dead. The server sends heartbeat comments on idle
+    /// streams, so this should be longer than the heartbeat interval
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn http(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Tracker to report connection health to. The source becomes valid once
+    /// a `put` has been written to the store
+    pub fn status(mut self, status: StatusTracker) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Listeners to notify of flags changed by stream events
+    pub fn changes(mut self, changes: FlagChanges) -> Self {
+        self.changes = changes;
+        self
+    }
+
+    /// Signal to stop the stream. It is checked between events and before
+    /// reconnecting, so a silent connection stops once it is closed or the
+    /// read times out
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     pub fn run(self, endpoint: &str, key: &str) -> Result<thread::JoinHandle<()>, ()> {
         if let Ok(url) = Url::parse(endpoint) {
-            let mut client = Client::new(url);
+            let key = key.to_string();
 
-            let mut headers: Headers = Headers::new();
-            headers.set(Authorization(key.to_string()));
-            headers.set(UserAgent::new("RustTest/".to_string() + VERSION));
+            Ok(thread::spawn(move || self.supervise(url, key)))
+        } else {
+            Err(())
+        }
+    }
 
-            client.default_headers = headers;
+    // Keeps the stream connected until the server rejects the credentials or
+    // it is shut down
+    fn supervise(mut self, url: Url, key: String) {
+        let client = match self.http.client(url.as_str(), self.read_timeout) {
+            Ok(client) => client,
+            Err(err) => {
+                error!("Failed to create stream client: {:?}", err);
+                self.status.off();
+                return;
+            }
+        };
+        let mut last_event_id = None;
 
-            Ok(thread::spawn(move || {
-                for msg in client {
-                    msg.map_err(StreamError::EventSource).and_then(|event| {
-                        Self::get_event_type(&event).and_then(|event_type| {
+        loop {
+            let res = self
+                .connect(&client, &url, &key, &last_event_id)
+                .and_then(|res| {
+                    self.backoff.connected();
+                    self.consume(res, &mut last_event_id)
+                });
+
+            // Connections dropped while shutting down are not failures
+            if self.shutdown.is_shutdown() {
+                info!("Stream shut down");
+                return;
+            }
+
+            match res {
+                Err(StreamError::Unauthorized(status)) => {
+                    self.status.off();
+                    error!(
+                        "Stream connection was rejected with status {}, no further updates will be received",
+                        status
+                    );
+                    return;
+                }
+                Err(err) => {
+                    warn!("Stream connection failed: {:?}", err);
+                    self.status.interrupted(err);
+                }
+                Ok(()) => (),
+            }
+
+            let delay = self.backoff.next_delay();
+            info!("Reconnecting stream in {:?}", delay);
+            if self.shutdown.sleep(delay) {
+                info!("Stream shut down");
+                return;
+            }
+        }
+    }
+
+    fn connect(
+        &self,
+        client: &Client,
+        url: &Url,
+        key: &str,
+        last_event_id: &Option<String>,
+    ) -> Result<Response, StreamError> {
+        let mut headers: Headers = Headers::new();
+        headers.set(Authorization(key.to_string()));
+        headers.set_raw("Accept", "text/event-stream");
+
+        if let Some(ref id) = *last_event_id {
+            headers.set_raw("Last-Event-ID", id.clone());
+        }
+
+        let res = client
+            .get(url.clone())
+            .headers(headers)
+            .send()
+            .map_err(StreamError::Connection)?;
+
+        match res.status() {
+            StatusCode::Unauthorized | StatusCode::Forbidden => {
+                Err(StreamError::Unauthorized(res.status()))
+            }
+            status if status.is_success() => Ok(res),
+            status => Err(StreamError::HttpStatus(status)),
+        }
+    }
+
+    fn consume(
+        &mut self,
+        res: Response,
+        last_event_id: &mut Option<String>,
+    ) -> Result<(), StreamError> {
+        let mut reader = BufReader::new(res);
+        let mut event = Event::new();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+
+            // Reads fail once the connection has been silent for longer than the
+            // read timeout, heartbeat comments included
+            let read = reader
+                .read_line(&mut line)
+                .map_err(|err| match err.kind() {
+                    IoErrorKind::TimedOut | IoErrorKind::WouldBlock => {
+                        StreamError::TimedOut(self.read_timeout)
+                    }
+                    _ => StreamError::Read(err),
+                })?;
+
+            if read == 0 {
+                return Err(StreamError::Closed);
+            }
+
+            if self.shutdown.is_shutdown() {
+                return Ok(());
+            }
+
+            match parse_line(line.as_str(), &mut event) {
+                ParseResult::Dispatch => {
+                    if event.id.is_some() {
+                        *last_event_id = event.id.clone();
+                    }
+
+                    if !event.is_empty() {
+                        let res = Self::get_event_type(&event).and_then(|event_type| {
                             self.process_data(&event_type, event.data.as_str())
-                        })
-                    });
+                        });
+
+                        if let Err(err) = res {
+                            warn!("Failed to process stream event: {:?}", err);
+                        }
+                    }
+
+                    event = Event::new();
                 }
-            }))
-        } else {
-            Err(())
+                ParseResult::SetRetry(retry) => self.backoff.set_initial(retry),
+                ParseResult::Comment | ParseResult::Next => (),
+            }
         }
     }
 
@@ -116,30 +399,356 @@ impl<S: Store> Streaming<S> {
         }
     }
 
+    fn init(&self, flags: HashMap<String, FeatureFlag>) -> Result<(), StreamError> {
+        self.changes
+            .init(&*self.store, flags)
+            .map_err(StreamError::Storage)?;
+        self.status.valid();
+        Ok(())
+    }
+
     fn process_data(&self, event_type: &StreamEventType, data: &str) -> Result<(), StreamError> {
         match *event_type {
             StreamEventType::Put => {
                 let flags = serde_json::from_str::<HashMap<String, FeatureFlag>>(data)
                     .map_err(StreamError::ParseData)?;
-                self.store.init(flags).map_err(StreamError::Storage)
+                self.init(flags)
             }
             StreamEventType::Patch => {
                 let patch = serde_json::from_str::<Patch>(data).map_err(StreamError::ParseData)?;
-                self.store
-                    .upsert(patch.key(), &patch.flag)
-                    .map_err(StreamError::Storage)
+
+                match flag_key(patch.path.as_str())? {
+                    Some(key) => self
+                        .changes
+                        .upsert(&*self.store, key, &patch.flag)
+                        .map_err(StreamError::Storage),
+                    None => Ok(()),
+                }
             }
             StreamEventType::Delete => {
                 let delete = serde_json::from_str::<Delete>(data).map_err(StreamError::ParseData)?;
-                self.store
-                    .delete(delete.key(), delete.version)
-                    .map_err(StreamError::Storage)
-            }
-            StreamEventType::IndirectPatch => match self.req.get(data) {
-                Ok(Some(flag)) => self.store.upsert(data, &flag).map_err(StreamError::Storage),
-                Ok(None) => Err(StreamError::FlagNotFound),
-                Err(err) => Err(StreamError::Request(err)),
+
+                match flag_key(delete.path.as_str())? {
+                    Some(key) => self
+                        .changes
+                        .delete(&*self.store, key, delete.version)
+                        .map_err(StreamError::Storage),
+                    None => Ok(()),
+                }
+            }
+            StreamEventType::IndirectPut => {
+                let flags = self.req.get_all().map_err(StreamError::Request)?;
+                self.init(flags)
+            }
+            StreamEventType::IndirectPatch => match flag_key(data.trim())? {
+                Some(key) => match self.req.get(key) {
+                    Ok(Some(flag)) => self
+                        .changes
+                        .upsert(&*self.store, key, &flag)
+                        .map_err(StreamError::Storage),
+                    Ok(None) => Err(StreamError::FlagNotFound),
+                    Err(err) => Err(StreamError::Request(err)),
+                },
+                None => Ok(()),
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use mem_store::MemStore;
+    use status::DataSourceStatus;
+    use stream::*;
+    use test_server;
+
+    const FLAGS: &'static str = "HTTP/1.1 200 OK\r\n\
+                                 Content-Type: text/event-stream\r\n\
+                                 Connection: close\r\n\r\n\
+                                 :heartbeat\n\n\
+                                 event: put\n\
+                                 id: 1\n\
+                                 data: {\"f1\": {\"key\": \"f1\", \"version\": 1}}\n\n";
+
+    const UNAUTHORIZED: &'static str = "HTTP/1.1 401 Unauthorized\r\n\
+                                        Content-Length: 0\r\n\
+                                        Connection: close\r\n\r\n";
+
+    const OPEN: &'static str = "HTTP/1.1 200 OK\r\n\
+                                Content-Type: text/event-stream\r\n\r\n\
+                                event: put\n\
+                                data: {\"f1\": {\"key\": \"f1\", \"version\": 1}}\n\n";
+
+    const SILENT: &'static str = "HTTP/1.1 200 OK\r\n\
+                                  Content-Type: text/event-stream\r\n\r\n\
+                                  :heartbeat\n\n";
+
+    fn serve(
+        responses: Vec<&'static str>,
+        shutdown: &Shutdown,
+    ) -> (String, Arc<Mutex<Vec<String>>>, thread::JoinHandle<()>) {
+        let (addr, requests, server) = test_server::serve_until(responses, shutdown.clone());
+        (addr + "/flags", requests, server)
+    }
+
+    fn streaming(store: Arc<MemStore>) -> Streaming<MemStore> {
+        let req = Arc::new(Requestor::new("http://127.0.0.1:1", "key").unwrap());
+        let backoff = Backoff::new(
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+            Duration::from_secs(60),
+        );
+
+        Streaming::new(store, req).backoff(backoff)
+    }
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut backoff = Backoff::new(
+            Duration::from_millis(100),
+            Duration::from_millis(1000),
+            Duration::from_secs(60),
+        );
+
+        for &max in &[100, 200, 400, 800, 1000, 1000] {
+            let delay = duration_millis(backoff.next_delay());
+            assert!(delay >= max / 2 && delay <= max, "{} not in {}", delay, max);
+        }
+    }
+
+    #[test]
+    fn test_backoff_resets_after_stable_connection() {
+        let mut backoff = Backoff::new(
+            Duration::from_millis(100),
+            Duration::from_millis(1000),
+            Duration::from_millis(0),
+        );
+
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.connected();
+
+        assert!(duration_millis(backoff.next_delay()) <= 100);
+    }
+
+    #[test]
+    fn test_reconnects_with_last_event_id() {
+        let shutdown = Shutdown::new();
+        let (addr, requests, server) = serve(vec![FLAGS, FLAGS], &shutdown);
+        let store = Arc::new(MemStore::new());
+
+        let handle = streaming(store.clone())
+            .shutdown(shutdown.clone())
+            .run(addr.as_str(), "key")
+            .unwrap();
+
+        for _ in 0..100 {
+            if requests.lock().unwrap().len() >= 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(store.get("f1").is_some());
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].contains("last-event-id"));
+        assert!(requests[1].contains("last-event-id: 1"));
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_stops_when_unauthorized() {
+        let shutdown = Shutdown::new();
+        let (addr, requests, server) = serve(vec![UNAUTHORIZED, UNAUTHORIZED], &shutdown);
+        let store = Arc::new(MemStore::new());
+
+        let handle = streaming(store).run(addr.as_str(), "key").unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnects_after_read_timeout() {
+        let shutdown = Shutdown::new();
+        let (addr, requests, server) = serve(vec![SILENT, SILENT], &shutdown);
+        let store = Arc::new(MemStore::new());
+
+        let handle = streaming(store)
+            .read_timeout(Duration::from_millis(200))
+            .shutdown(shutdown.clone())
+            .run(addr.as_str(), "key")
+            .unwrap();
+
+        for _ in 0..100 {
+            if requests.lock().unwrap().len() >= 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_heartbeats_keep_the_connection_alive() {
+        let shutdown = Shutdown::new();
+        let (addr, requests, server) = test_server::serve_heartbeats(
+            OPEN,
+            Duration::from_millis(50),
+            30,
+            shutdown.clone(),
+        );
+        let store = Arc::new(MemStore::new());
+
+        let handle = streaming(store.clone())
+            .read_timeout(Duration::from_millis(200))
+            .shutdown(shutdown.clone())
+            .run((addr + "/flags").as_str(), "key")
+            .unwrap();
+
+        // Well past the read timeout, but before the heartbeats run out
+        thread::sleep(Duration::from_millis(1000));
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(store.get("f1").is_some());
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_initialized_after_put() {
+        let shutdown = Shutdown::new();
+        let (addr, _, server) = serve(vec![SILENT, FLAGS], &shutdown);
+        let store = Arc::new(MemStore::new());
+        let status = StatusTracker::new();
+        let updates = status.subscribe();
+
+        let handle = streaming(store.clone())
+            .read_timeout(Duration::from_millis(200))
+            .status(status.clone())
+            .shutdown(shutdown.clone())
+            .run(addr.as_str(), "key")
+            .unwrap();
+
+        assert!(status.initialized().wait(Duration::from_secs(5)));
+        assert!(store.get("f1").is_some());
+        assert_eq!(updates.recv().unwrap(), DataSourceStatus::Valid);
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("/flags/my-flag").unwrap(),
+            (DataKind::Flags, "my-flag")
+        );
+        assert_eq!(
+            parse_path("/segments/beta").unwrap(),
+            (DataKind::Segments, "beta")
+        );
+        assert_eq!(
+            parse_path("/my-flag").unwrap(),
+            (DataKind::Flags, "my-flag")
+        );
+
+        let invalid = [
+            "",
+            "/",
+            "my-flag",
+            "/flags/",
+            "/rules/my-flag",
+            "/flags/a/b",
+        ];
+
+        for path in &invalid {
+            match parse_path(path) {
+                Err(StreamError::InvalidPath(_)) => (),
+                res => panic!("{:?} parsed as {:?}", path, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_patch_and_delete_by_path() {
+        let store = Arc::new(MemStore::new());
+        let stream = streaming(store.clone());
+
+        stream
+            .process_data(
+                &StreamEventType::Patch,
+                r#"{"path": "/flags/f1", "data": {"key": "f1", "version": 1}}"#,
+            )
+            .unwrap();
+        assert!(store.get("f1").is_some());
+
+        stream
+            .process_data(
+                &StreamEventType::Patch,
+                r#"{"path": "/segments/s1", "data": {"key": "s1", "version": 1}}"#,
+            )
+            .unwrap();
+        assert!(store.get("s1").is_none());
+
+        stream
+            .process_data(
+                &StreamEventType::Delete,
+                r#"{"path": "/flags/f1", "version": 2}"#,
+            )
+            .unwrap();
+        assert!(store.get("f1").is_none());
+
+        match stream.process_data(&StreamEventType::Delete, r#"{"path": "", "version": 3}"#) {
+            Err(StreamError::InvalidPath(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_indirect_put_fetches_all_flags() {
+        let shutdown = Shutdown::new();
+        let (addr, requests, server) = serve(
+            vec![
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Length: 35\r\n\
+                 Connection: close\r\n\r\n\
+                 {\"f1\": {\"key\": \"f1\", \"version\": 1}}",
+            ],
+            &shutdown,
+        );
+        let store = Arc::new(MemStore::new());
+        let status = StatusTracker::new();
+        let req = Arc::new(Requestor::new(addr, "key").unwrap());
+        let stream = Streaming::new(store.clone(), req).status(status.clone());
+
+        stream
+            .process_data(&StreamEventType::IndirectPut, "")
+            .unwrap();
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(store.get("f1").is_some());
+        assert_eq!(status.status(), DataSourceStatus::Valid);
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use status::Shutdown;
+
+/// Serves each response to one connection in turn and records the requests,
+/// lowercased. Connections are held open unless the response asks for them to
+/// be closed
+pub fn serve(responses: Vec<&'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let (addr, requests, _) = serve_until(responses, Shutdown::new());
+    (addr, requests)
+}
+
+/// As `serve`, but stops and closes the held connections once `shutdown` is
+/// signalled
+pub fn serve_until(
+    responses: Vec<&'static str>,
+    shutdown: Shutdown,
+) -> (String, Arc<Mutex<Vec<String>>>, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+
+    let r = requests.clone();
+    let handle = thread::spawn(move || {
+        let mut held = vec![];
+
+        for response in responses {
+            let mut stream = match accept(&listener, &shutdown) {
+                Some(stream) => stream,
+                None => return,
+            };
+            let request = read_request(&mut stream);
+            r.lock().unwrap().push(request.to_lowercase());
+            stream.write_all(response.as_bytes()).unwrap();
+
+            if !response.contains("Connection: close") {
+                held.push(stream);
+            }
+        }
+
+        shutdown.sleep(Duration::from_secs(60));
+    });
+
+    (addr, requests, handle)
+}
+
+/// Serves `response` to every connection and follows it with `heartbeats`
+/// comments, one every `interval`, before closing the connection
+pub fn serve_heartbeats(
+    response: &'static str,
+    interval: Duration,
+    heartbeats: usize,
+    shutdown: Shutdown,
+) -> (String, Arc<Mutex<Vec<String>>>, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+
+    let r = requests.clone();
+    let handle = thread::spawn(move || {
+        while let Some(mut stream) = accept(&listener, &shutdown) {
+            let request = read_request(&mut stream);
+            r.lock().unwrap().push(request.to_lowercase());
+            stream.write_all(response.as_bytes()).unwrap();
+
+            for _ in 0..heartbeats {
+                if shutdown.sleep(interval) || stream.write_all(b":\n").is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    (addr, requests, handle)
+}
+
+// Waits for the next connection, or none once shut down
+fn accept(listener: &TcpListener, shutdown: &Shutdown) -> Option<TcpStream> {
+    listener.set_nonblocking(true).unwrap();
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false).unwrap();
+                return Some(stream);
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                if shutdown.sleep(Duration::from_millis(10)) {
+                    return None;
+                }
+            }
+            Err(err) => panic!("accept failed: {}", err),
+        }
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+
+    while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    String::from_utf8_lossy(&request).into_owned()
+}
@@ -138,24 +138,37 @@ impl User {
         self.key.as_str()
     }
 
-    pub fn bucket(&self, key: &str, by: &str, salt: &str) -> f64 {
-        if let Some(ref val) = self.get_for_eval(by) {
-            let mut source = [key, salt, val].join(".");
+    /// Buckets the user into [0, 1) by hashing the attribute named by `by`.
+    /// Rollouts with a seed hash against the seed instead of the flag key and
+    /// salt
+    pub fn bucket(&self, key: &str, by: &str, salt: &str, seed: Option<i64>) -> f64 {
+        if let Some(val) = self.get_for_eval(by) {
+            // Feed the hash input piecewise rather than joining it into a string
+            let mut hasher = Sha1::default();
+
+            if let Some(seed) = seed {
+                hasher.input(seed.to_string().as_bytes());
+            } else {
+                hasher.input(key.as_bytes());
+                hasher.input(b".");
+                hasher.input(salt.as_bytes());
+            }
+
+            hasher.input(b".");
+            hasher.input(val.as_bytes());
 
             if let Some(ref scnd) = self.secondary {
-                source.push('.');
-                source.push_str(scnd);
+                hasher.input(b".");
+                hasher.input(scnd.as_bytes());
             }
 
-            let hash = Sha1::digest_str(source.as_str());
-            let hex = hash.iter()
-                .map(|b| format!("{:02x}", b))
-                .collect::<String>();
-            let val_res = i64::from_str_radix(&hex[..15], 16);
+            // The first 15 hex digits of the digest are its top 60 bits
+            let hash = hasher.result();
+            let val = hash.iter()
+                .take(8)
+                .fold(0u64, |acc, b| (acc << 8) | *b as u64) >> 4;
 
-            val_res
-                .ok()
-                .map_or(0.0, |val| val as f64 / LONG_SCALE as f64)
+            val as f64 / LONG_SCALE as f64
         } else {
             0.0
         }
@@ -184,17 +197,32 @@ mod tests {
     fn test_bucket_user() {
         let user_key_a = "userKeyA";
         let user_a = UserBuilder::new(user_key_a).build();
-        let bucket_a = user_a.bucket("hashKey", "key", "saltyA");
+        let bucket_a = user_a.bucket("hashKey", "key", "saltyA", None);
         assert_eq!(0.4215758743392494, bucket_a);
 
         let user_key_b = "userKeyB";
         let user_b = UserBuilder::new(user_key_b).build();
-        let bucket_b = user_b.bucket("hashKey", "key", "saltyA");
+        let bucket_b = user_b.bucket("hashKey", "key", "saltyA", None);
         assert_eq!(0.6708484965703435, bucket_b);
 
         let user_key_c = "userKeyC";
         let user_c = UserBuilder::new(user_key_c).build();
-        let bucket_c = user_c.bucket("hashKey", "key", "saltyA");
+        let bucket_c = user_c.bucket("hashKey", "key", "saltyA", None);
         assert_eq!(0.1034310617276969, bucket_c);
     }
+
+    #[test]
+    fn test_bucket_user_with_seed() {
+        let user_a = UserBuilder::new("userKeyA").build();
+        let bucket_a = user_a.bucket("hashKey", "key", "saltyA", Some(61));
+        assert_eq!(0.0980120652476667, bucket_a);
+
+        let user_b = UserBuilder::new("userKeyB").build();
+        let bucket_b = user_b.bucket("hashKey", "key", "saltyA", Some(61));
+        assert_eq!(0.1448377737757314, bucket_b);
+
+        let user_c = UserBuilder::new("userKeyC").build();
+        let bucket_c = user_c.bucket("hashKey", "key", "saltyA", Some(61));
+        assert_eq!(0.9242640945125551, bucket_c);
+    }
 }